@@ -1,227 +1,909 @@
+use crate::change::{self, Change, ChangeSet, Diff};
 use crate::error::Error;
 use crate::node::Node;
-use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
+use crate::node_type::{Key, KeyValuePair, NodeType, Offset, PageId};
 use crate::page::Page;
+use crate::page_layout::{KEY_SIZE, PAGE_SIZE};
 use crate::pager::Pager;
-use crate::wal::Wal;
+use crate::storage::{FileStorage, MemoryStorage, Storage};
+use crate::wal::{Wal, WalRecord};
 use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
-use std::path::Path;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// B+Tree properties.
 pub const MAX_BRANCHING_FACTOR: usize = 154;
 pub const NODE_KEYS_LIMIT: usize = MAX_BRANCHING_FACTOR - 1;
 
+/// UUIDv7 keys start with a 48 bit timestamp.
+const UUID_V7_TIMESTAMP_SIZE: usize = 6;
+
+/// The earlier and the later of two versions of the tree compared to one another, see `Versions`.
+const EARLIER: usize = 0;
+const LATER: usize = 1;
+
 /// BTree struct represents an on-disk B+tree.
 /// Each node is persisted in the table file, the leaf nodes contain the values.
-pub struct BTree {
-    pager: Pager,
+/// The table file and the WAL are kept in a Storage, which is a file on disk by default.
+pub struct BTree<S: Storage = FileStorage> {
+    pager: Pager<S>,
     b: usize,
-    wal: Wal,
+    wal: Wal<S>,
+    /// A read-only tree follows the roots committed by the writer of the tree.
+    read_only: bool,
+    /// The id of the latest committed root, if any.
+    root: Option<PageId>,
+    /// Whether internal nodes keep the number of pairs held under each of their children.
+    counted: bool,
+    /// The changes made by the write in progress, logged alongside its commit.
+    changes: Vec<Change>,
+    /// The channels every committed change set is published to.
+    subscribers: Vec<Sender<ChangeSet>>,
+    /// The lock file held by the writer of a tree file, until the tree is dropped.
+    #[allow(dead_code)]
+    lock: Option<FileStorage>,
 }
 
 /// BtreeBuilder is a Builder for the BTree struct.
-pub struct BTreeBuilder {
+/// The storage parameter tells whether the tree is kept in a file on disk (the default)
+/// or purely in memory.
+pub struct BTreeBuilder<S: Storage = FileStorage> {
     /// Path to the tree file.
-    path: &'static Path,
+    path: PathBuf,
+    /// Path to the WAL, defaults to the path of the tree file suffixed by '-wal'.
+    wal_path: Option<PathBuf>,
+    /// Whether the tree is opened for reading only.
+    read_only: bool,
     /// The BTree parameter, an inner node contains no more than 2*b-1 keys and no less than b-1 keys
     /// and no more than 2*b children and no less than b children.
     b: usize,
+    /// Whether internal nodes keep subtree counts, see `BTreeBuilder::subtree_counts`.
+    subtree_counts: bool,
+    /// Whether pages are mapped to their offsets by a page table, see `BTreeBuilder::page_table`.
+    page_table: bool,
+    /// The key pages are encrypted with, if any.
+    #[cfg(feature = "encryption")]
+    key: Option<[u8; 32]>,
+    storage: PhantomData<S>,
 }
 
-impl BTreeBuilder {
-    pub fn new() -> BTreeBuilder {
+impl BTreeBuilder<FileStorage> {
+    pub fn new() -> BTreeBuilder<FileStorage> {
         BTreeBuilder {
-            path: Path::new(""),
+            path: PathBuf::new(),
+            wal_path: None,
+            read_only: false,
             b: 0,
+            subtree_counts: false,
+            page_table: true,
+            #[cfg(feature = "encryption")]
+            key: None,
+            storage: PhantomData,
         }
     }
 
-    pub fn path(mut self, path: &'static Path) -> BTreeBuilder {
-        self.path = path;
+    pub fn path(mut self, path: impl AsRef<Path>) -> BTreeBuilder<FileStorage> {
+        self.path = path.as_ref().to_path_buf();
         self
     }
 
-    pub fn b_parameter(mut self, b: usize) -> BTreeBuilder {
-        self.b = b;
+    /// wal_path overrides the path of the WAL, which is otherwise kept next to the tree file.
+    pub fn wal_path(mut self, path: impl AsRef<Path>) -> BTreeBuilder<FileStorage> {
+        self.wal_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// read_only opens the tree for reading only, alongside at most one writer of the tree
+    /// (possibly in another process). A read-only tree picks up the roots committed by the
+    /// writer as it goes, and fails any insert or delete with `Error::ReadOnly`.
+    ///
+    /// Neither the tree file nor its WAL are ever modified or created, nor opened with
+    /// write permission, so that read-only mounts and snapshot copies of a tree can be read.
+    pub fn read_only(mut self, read_only: bool) -> BTreeBuilder<FileStorage> {
+        self.read_only = read_only;
         self
     }
 
-    pub fn build(&self) -> Result<BTree, Error> {
-        if self.path.to_string_lossy() == "" {
+    /// build opens (or creates) the tree file and its WAL.
+    ///
+    /// Advisory locks are held for as long as the tree is open: every reader and writer holds
+    /// a shared lock on the tree file, so that the file is never overwritten while in use,
+    /// and the writer holds an exclusive lock on a lock file next to the tree file (e.g.
+    /// '/tmp/db-lock' for '/tmp/db') as well as on the WAL, so that two writers never share
+    /// a tree file, whatever their WALs. Fails with `Error::Locked` if the tree already has
+    /// a writer, or if it is being overwritten.
+    pub fn build(&self) -> Result<BTree<FileStorage>, Error> {
+        if self.path.as_os_str().is_empty() {
             return Err(Error::UnexpectedError);
         }
+
+        let wal_path = match &self.wal_path {
+            Some(wal_path) => wal_path.clone(),
+            None => default_wal_path(&self.path)?,
+        };
+        if self.read_only {
+            let tree = FileStorage::open_read_only(&self.path)?;
+            tree.try_lock_shared()?;
+            let wal = FileStorage::open_read_only(&wal_path)?;
+            return BTree::open_read_only(self.pager(tree)?, Wal::open_read_only(wal)?, self.b);
+        }
+        let lock = FileStorage::new(&lock_path(&self.path)?)?;
+        lock.try_lock()?;
+        let tree = FileStorage::new(&self.path)?;
+        tree.try_lock_shared()?;
+        let wal = FileStorage::new(&wal_path)?;
+        wal.try_lock()?;
+        let mut btree = self.build_with_storage(tree, wal)?;
+        btree.lock = Some(lock);
+        Ok(btree)
+    }
+}
+
+impl BTreeBuilder<MemoryStorage> {
+    /// in_memory returns a builder for a tree which is kept purely in memory,
+    /// see `BTree::dump` for persisting it later on.
+    pub fn in_memory() -> BTreeBuilder<MemoryStorage> {
+        BTreeBuilder {
+            path: PathBuf::new(),
+            wal_path: None,
+            read_only: false,
+            b: 0,
+            subtree_counts: false,
+            page_table: true,
+            #[cfg(feature = "encryption")]
+            key: None,
+            storage: PhantomData,
+        }
+    }
+
+    pub fn build(&self) -> Result<BTree<MemoryStorage>, Error> {
+        self.build_with_storage(MemoryStorage::new(), MemoryStorage::new())
+    }
+}
+
+impl<S: Storage> BTreeBuilder<S> {
+    pub fn b_parameter(mut self, b: usize) -> BTreeBuilder<S> {
+        self.b = b;
+        self
+    }
+
+    /// subtree_counts keeps the number of pairs held under every child of every internal node,
+    /// so that `len`, `count_range`, `rank` and `select` take a single descent from the root
+    /// rather than a scan. Counts are maintained by every write from then on, and are added
+    /// to the nodes of an existing tree when it is opened.
+    pub fn subtree_counts(mut self, subtree_counts: bool) -> BTreeBuilder<S> {
+        self.subtree_counts = subtree_counts;
+        self
+    }
+
+    /// page_table maps the ids of pages to the offsets of their latest copies (the default),
+    /// so that a node which is written anew keeps its id: the nodes referring to it are left
+    /// as they are, and leaves are linked to their siblings by id.
+    ///
+    /// Without a page table the id of a page is its position in the tree file, so that WAL
+    /// records list no pages and there is no table to rebuild on open. A node written anew
+    /// takes a new id in turn, so every write rewrites the path from the root down to the
+    /// nodes it changes, and leaves are not linked: scans step from leaf to leaf along the
+    /// path from the root instead. A tree goes on with or without a page table as it was
+    /// created, whatever is asked when it is opened again.
+    pub fn page_table(mut self, page_table: bool) -> BTreeBuilder<S> {
+        self.page_table = page_table;
+        self
+    }
+
+    /// encryption_key encrypts every page of the tree under a given 256 bit key,
+    /// which has to be given again whenever the tree is opened. Pages which were tampered
+    /// with (or are read under another key) fail to be read with `Error::Corrupted`.
+    /// The WAL holds the ids and offsets of pages in the clear, while the changes logged
+    /// alongside every commit (see `BTree::subscribe`) are encrypted as well. Every record of
+    /// the WAL is authenticated, so that a tampered page table fails with `Error::Corrupted`.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> BTreeBuilder<S> {
+        self.key = Some(key);
+        self
+    }
+
+    /// build_with_storage builds a tree whose table and WAL are kept in the given storages.
+    /// If the WAL already holds a root the tree is reopened at it, otherwise a new tree is started.
+    pub fn build_with_storage<T: Storage>(&self, tree: T, wal: T) -> Result<BTree<T>, Error> {
         if self.b == 0 {
             return Err(Error::UnexpectedError);
         }
+        BTree::open(
+            self.pager(tree)?,
+            Wal::new(wal)?,
+            self.b,
+            self.subtree_counts,
+        )
+    }
 
-        let mut pager = Pager::new(self.path)?;
-        let root = Node::new(NodeType::Leaf(vec![]), true, None);
-        let root_offset = pager.write_page(Page::try_from(&root)?)?;
-        let parent_directory = self.path.parent().unwrap_or_else(|| Path::new("/tmp"));
-        let mut wal = Wal::new(parent_directory.to_path_buf())?;
-        wal.set_root(root_offset)?;
-
-        Ok(BTree {
-            pager,
-            b: self.b,
-            wal,
-        })
+    /// pager returns the pager of a tree kept in a given storage.
+    fn pager<T: Storage>(&self, tree: T) -> Result<Pager<T>, Error> {
+        #[cfg(feature = "encryption")]
+        let mut pager = match &self.key {
+            Some(key) => Pager::encrypted(tree, key)?,
+            None => Pager::new(tree)?,
+        };
+        #[cfg(not(feature = "encryption"))]
+        let mut pager = Pager::new(tree)?;
+        pager.set_page_table(self.page_table);
+        Ok(pager)
     }
 }
 
+/// default_wal_path returns the path of the WAL belonging to the tree file at a given path,
+/// which is named after the tree file, e.g. the WAL of '/tmp/db' is '/tmp/db-wal'.
+fn default_wal_path(path: &Path) -> Result<PathBuf, Error> {
+    suffixed_path(path, "-wal")
+}
+
+/// lock_path returns the path of the file the writer of the tree file at a given path locks,
+/// e.g. '/tmp/db-lock' for '/tmp/db'.
+fn lock_path(path: &Path) -> Result<PathBuf, Error> {
+    suffixed_path(path, "-lock")
+}
+
+/// suffixed_path returns the path of a file named after the file at a given path,
+/// followed by a given suffix.
+fn suffixed_path(path: &Path, suffix: &str) -> Result<PathBuf, Error> {
+    let mut file_name = path
+        .file_name()
+        .ok_or(Error::UnexpectedError)?
+        .to_os_string();
+    file_name.push(suffix);
+    Ok(path.with_file_name(file_name))
+}
+
 impl Default for BTreeBuilder {
     // A default BTreeBuilder provides a builder with:
     // - b parameter set to 200
     // - path set to '/tmp/db'.
     fn default() -> Self {
-        BTreeBuilder::new()
-            .b_parameter(200)
-            .path(Path::new("/tmp/db"))
+        BTreeBuilder::new().b_parameter(200).path("/tmp/db")
     }
 }
 
-impl BTree {
-    fn is_node_full(&self, node: &Node) -> Result<bool, Error> {
-        match &node.node_type {
-            NodeType::Leaf(pairs) => Ok(pairs.len() == (2 * self.b)),
-            NodeType::Internal(_, keys) => Ok(keys.len() == (2 * self.b - 1)),
-            NodeType::Unexpected => Err(Error::UnexpectedError),
+/// Op is a write to the tree, applied alongside others by `BTree::apply_batch`.
+#[derive(Clone, Debug)]
+pub enum Op {
+    /// Insert a key value pair, replacing the pair of the key if any.
+    Insert(KeyValuePair),
+    /// Delete a key, which fails with `Error::KeyNotFound` if the tree does not hold it.
+    Delete(Key),
+}
+
+impl Op {
+    fn key(&self) -> Key {
+        match self {
+            Op::Insert(kv) => Key(kv.key),
+            Op::Delete(key) => key.clone(),
+        }
+    }
+
+    /// change returns the change the write makes to the tree.
+    fn change(&self) -> Change {
+        match self {
+            Op::Insert(kv) => Change::Put(kv.clone()),
+            Op::Delete(key) => Change::Delete(key.clone()),
+        }
+    }
+
+    /// apply applies the write to the pairs of the leaf holding its key.
+    fn apply(self, pairs: &mut Vec<KeyValuePair>) -> Result<(), Error> {
+        match self {
+            // A key holds a single pair, which is replaced if the key is written again.
+            Op::Insert(kv) => match pairs.binary_search(&kv) {
+                Ok(idx) => pairs[idx] = kv,
+                Err(idx) => pairs.insert(idx, kv),
+            },
+            Op::Delete(key) => {
+                let key_idx = pairs
+                    .binary_search_by_key(&key, |kv| Key(kv.key))
+                    .map_err(|_| Error::KeyNotFound)?;
+                pairs.remove(key_idx);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> BTree<S> {
+    /// open reopens the tree at the last root committed to the WAL,
+    /// or starts a new tree if none was committed yet.
+    /// A tree whose nodes keep subtree counts goes on keeping them, whether asked to or not.
+    fn open(
+        pager: Pager<S>,
+        wal: Wal<S>,
+        b: usize,
+        subtree_counts: bool,
+    ) -> Result<BTree<S>, Error> {
+        let mut btree = BTree {
+            pager,
+            b,
+            wal,
+            read_only: false,
+            root: None,
+            counted: subtree_counts,
+            changes: Vec::new(),
+            subscribers: Vec::new(),
+            lock: None,
+        };
+        btree.refresh()?;
+        let root_id = match btree.root {
+            Some(root_id) => root_id,
+            None => {
+                let root_id = btree.pager.allocate();
+                let root = Node::new(NodeType::Leaf(vec![]), true);
+                btree.write_node(&root_id, &root)?;
+                btree.commit(root_id)?;
+                root_id
+            }
+        };
+        let root = btree.get_node(&root_id)?;
+        if let NodeType::Internal(_, _, counts) = &root.node_type {
+            if !counts.is_empty() {
+                btree.counted = true;
+            } else if subtree_counts {
+                let (root_id, _) = btree.fill_counts(&root_id, root)?;
+                btree.commit(root_id)?;
+            }
+        }
+        Ok(btree)
+    }
+
+    /// fill_counts adds subtree counts to the internal nodes of the sub tree rooted at a node
+    /// given by its id, and returns the id the node was written under (see `Pager::relocate`)
+    /// alongside the number of pairs held under it.
+    fn fill_counts(&mut self, id: &PageId, mut node: Node) -> Result<(PageId, usize), Error> {
+        let count = match node.node_type {
+            NodeType::Internal(ref mut children, _, ref mut counts) => {
+                counts.clear();
+                for child_id in children.iter_mut() {
+                    let child = self.get_node(child_id)?;
+                    let (id, count) = self.fill_counts(child_id, child)?;
+                    *child_id = id;
+                    counts.push(count);
+                }
+                counts.iter().sum()
+            }
+            NodeType::Leaf(ref pairs) => return Ok((*id, pairs.len())),
+            NodeType::Unexpected => return Err(Error::UnexpectedError),
+        };
+        let id = self.pager.relocate(id);
+        self.write_node(&id, &node)?;
+        Ok((id, count))
+    }
+
+    /// open_read_only opens the tree at the last root committed to the WAL,
+    /// without modifying either of them.
+    fn open_read_only(pager: Pager<S>, wal: Wal<S>, b: usize) -> Result<BTree<S>, Error> {
+        let mut btree = BTree {
+            pager,
+            b,
+            wal,
+            read_only: true,
+            root: None,
+            counted: false,
+            changes: Vec::new(),
+            subscribers: Vec::new(),
+            lock: None,
+        };
+        btree.root()?;
+        Ok(btree)
+    }
+
+    /// check_writable fails with `Error::ReadOnly` if the tree was opened for reading only.
+    fn check_writable(&self) -> Result<(), Error> {
+        match self.read_only {
+            true => Err(Error::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// refresh replays the commits appended to the WAL since it was last read:
+    /// all of them when the tree is opened, and those of the writer for a read-only tree.
+    fn refresh(&mut self) -> Result<(), Error> {
+        for record in self.wal.replay()? {
+            let metadata = self.pager.open_record(&record)?;
+            // The first commit of a tree writes its root, which is only logged alongside
+            // the offset of its page if the tree has a page table.
+            if record.sequence == 1 {
+                self.pager.set_page_table(!record.pages.is_empty());
+            }
+            self.pager.apply(&record.pages);
+            self.root = Some(record.root);
+            if self.subscribers.is_empty() {
+                continue;
+            }
+            let changes = change::decode(&metadata)?;
+            if !changes.is_empty() {
+                self.publish(ChangeSet {
+                    sequence: record.sequence,
+                    changes,
+                });
+            }
         }
+        Ok(())
+    }
+
+    /// root returns the id of the latest committed root.
+    fn root(&mut self) -> Result<PageId, Error> {
+        self.refresh()?;
+        self.root.ok_or(Error::RootNotFound)
+    }
+
+    fn get_node(&mut self, id: &PageId) -> Result<Node, Error> {
+        Node::try_from(self.pager.get_page(id)?)
     }
 
+    fn write_node(&mut self, id: &PageId, node: &Node) -> Result<(), Error> {
+        self.pager.write_page(id, Page::try_from(node)?)
+    }
+
+    /// is_node_underflow checks whether a node holds too few keys, and takes up too little of its
+    /// page, to be left on its own rather than merged with a sibling.
+    /// Nodes split as their pages fill up take up at least a quarter of a page, however few
+    /// keys they hold, so that merges are only triggered by deletes.
     fn is_node_underflow(&self, node: &Node) -> Result<bool, Error> {
-        match &node.node_type {
-            // A root cannot really be "underflowing" as it can contain less than b-1 keys / pointers.
-            NodeType::Leaf(pairs) => Ok(pairs.len() < (self.b - 1) && !node.is_root),
-            NodeType::Internal(_, keys) => Ok(keys.len() < (self.b - 1) && !node.is_root),
-            NodeType::Unexpected => Err(Error::UnexpectedError),
+        // A root cannot really be "underflowing" as it can contain less than b-1 keys / pointers.
+        let few_keys = match &node.node_type {
+            NodeType::Leaf(pairs) => pairs.len() < (self.b - 1),
+            NodeType::Internal(_, keys, _) => keys.len() < (self.b - 1),
+            NodeType::Unexpected => return Err(Error::UnexpectedError),
+        };
+        Ok(few_keys && !node.is_root && Page::encoded_len(node)? < PAGE_SIZE / 4)
+    }
+
+    /// commit makes the pages written so far durable and only then logs them alongside the new root,
+    /// so that a crash can never leave the WAL pointing at a partially written tree.
+    /// The changes made to the pairs of the tree are logged alongside the root, and published
+    /// to the subscribers of the tree once committed.
+    fn commit(&mut self, root_id: PageId) -> Result<(), Error> {
+        self.pager.sync()?;
+        let changes = std::mem::take(&mut self.changes);
+        let pages = self.pager.changes();
+        let aad = WalRecord::authenticated_data(self.wal.sequence() + 1, root_id, &pages);
+        let metadata = match changes.is_empty() {
+            true => Vec::new(),
+            false => change::encode(&changes),
+        };
+        let metadata = self.pager.seal_record(metadata, &aad)?;
+        self.wal.commit(root_id, pages, metadata)?;
+        self.pager.commit();
+        self.root = Some(root_id);
+        if !changes.is_empty() {
+            self.publish(ChangeSet {
+                sequence: self.wal.sequence(),
+                changes,
+            });
+        }
+        Ok(())
+    }
+
+    /// publish sends a committed change set to every subscriber of the tree,
+    /// forgetting the subscribers which hung up.
+    fn publish(&mut self, change_set: ChangeSet) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(change_set.clone()).is_ok());
+    }
+
+    /// subscribe returns a channel which receives the changes of every commit from then on,
+    /// in order, as they are committed. A read-only tree receives the changes committed by
+    /// the writer of the tree as it picks them up.
+    pub fn subscribe(&mut self) -> Receiver<ChangeSet> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// changes_since reads back the changes of every commit past a given sequence number from
+    /// the WAL, in order, e.g. to catch up with the commits missed by a consumer after a restart.
+    /// Commits which did not change any pair (such as the one starting a tree, or those made
+    /// by backups) are left out.
+    pub fn changes_since(&mut self, sequence: u64) -> Result<Vec<ChangeSet>, Error> {
+        self.refresh()?;
+        let mut change_sets = Vec::new();
+        for record in self.wal.records_since(sequence)? {
+            let changes = change::decode(&self.pager.open_record(&record)?)?;
+            if changes.is_empty() {
+                continue;
+            }
+            change_sets.push(ChangeSet {
+                sequence: record.sequence,
+                changes,
+            });
+        }
+        Ok(change_sets)
+    }
+
+    /// sequence returns the sequence number of the latest commit, which names the current
+    /// version of the tree, see `diff`.
+    pub fn sequence(&mut self) -> Result<u64, Error> {
+        self.refresh()?;
+        Ok(self.wal.sequence())
+    }
+
+    /// diff returns the pairs which were added, removed or changed between two versions of the
+    /// tree, named by the sequence numbers of their commits, in key order. Sequence number zero
+    /// stands for the empty tree preceding the first commit.
+    ///
+    /// Committed pages are never overwritten, so every version of the tree can be read back.
+    /// The WAL is read once from the earlier version on, to find the pages written in between
+    /// and where both versions keep them. Both versions are then walked together from their
+    /// roots, skipping the sub trees they share without any page written in between, so that
+    /// only the nodes leading to what changed are read, each of them once.
+    pub fn diff(&mut self, from: u64, to: u64) -> Result<Vec<Diff>, Error> {
+        if from > to {
+            return Ok(self
+                .diff(to, from)?
+                .into_iter()
+                .map(|diff| match diff {
+                    Diff::Added(pair) => Diff::Removed(pair),
+                    Diff::Removed(pair) => Diff::Added(pair),
+                    Diff::Changed(previous, pair) => Diff::Changed(pair, previous),
+                })
+                .collect());
+        }
+        let mut versions = self.versions(from, to)?;
+        let mut pairs = [BTreeMap::new(), BTreeMap::new()];
+        versions.walk(&mut self.pager, |version, _, node| {
+            if let NodeType::Leaf(leaf) = &node.node_type {
+                pairs[version].extend(leaf.iter().map(|pair| (pair.key, pair.clone())));
+            }
+        })?;
+        let [pairs_from, pairs_to] = pairs;
+
+        let keys: BTreeSet<&[u8; KEY_SIZE]> = pairs_from.keys().chain(pairs_to.keys()).collect();
+        let mut diff = Vec::new();
+        for key in keys {
+            match (pairs_from.get(key), pairs_to.get(key)) {
+                (None, Some(pair)) => diff.push(Diff::Added(pair.clone())),
+                (Some(pair), None) => diff.push(Diff::Removed(pair.clone())),
+                (Some(previous), Some(pair)) if previous != pair => {
+                    diff.push(Diff::Changed(previous.clone(), pair.clone()))
+                }
+                _ => {}
+            }
+        }
+        Ok(diff)
+    }
+
+    /// versions reads the WAL records from the commit with a given sequence number up to the
+    /// latest one, once, to find the roots of the tree as of that commit and of a later one,
+    /// and where either version keeps its pages, see `Versions`.
+    fn versions(&mut self, earlier: u64, later: u64) -> Result<Versions, Error> {
+        self.refresh()?;
+        if later > self.wal.sequence() {
+            return Err(Error::RootNotFound);
+        }
+        let mut versions = Versions::default();
+        for record in self.wal.records_since(earlier.saturating_sub(1))? {
+            self.pager.open_record(&record)?;
+            if record.sequence == earlier {
+                versions.roots[EARLIER] = Some(record.root);
+            } else if record.sequence <= later {
+                versions.roots[LATER] = Some(record.root);
+                for (id, offset, previous) in record.pages {
+                    versions.written.entry(id).or_insert([previous, None])[LATER] = Some(offset);
+                }
+            } else {
+                for (id, _, previous) in record.pages {
+                    versions.rewritten.entry(id).or_insert(previous);
+                }
+            }
+        }
+        if earlier == later {
+            versions.roots[LATER] = versions.roots[EARLIER];
+        }
+        Ok(versions)
+    }
+
+    /// backup_to backs the tree up as of its latest commit into a new tree file at a given path,
+    /// and its WAL next to it, and returns the sequence number of the commit backed up.
+    /// Only the pages reachable from the root of the tree are copied, so the backup holds
+    /// this version of the tree alone. Committed pages are never overwritten, so a reader can
+    /// back a tree up while its writer goes on writing to it.
+    /// Fails with `Error::Locked` rather than overwrite a tree which is currently open.
+    pub fn backup_to(&mut self, path: impl AsRef<Path>) -> Result<u64, Error> {
+        self.backup(path.as_ref(), None)
+    }
+
+    /// incremental_backup_to brings a backup of the tree up to date with its latest commit,
+    /// given the sequence number of the commit it was last brought up to, and returns the
+    /// sequence number of the commit backed up. Pages are only ever appended to the tree file,
+    /// so pages written since are found at new offsets: only those pages are copied, and
+    /// appended to the backup alongside a new commit to its WAL.
+    /// Fails with `Error::Conflict` unless the backup was last brought up to that very commit.
+    pub fn incremental_backup_to(
+        &mut self,
+        path: impl AsRef<Path>,
+        since: u64,
+    ) -> Result<u64, Error> {
+        self.backup(path.as_ref(), Some(since))
+    }
+
+    /// backup copies the pages reachable from the latest root of the tree to the backup at a
+    /// given path, but for those which were already reachable, at the same offset, as of the
+    /// commit the backup was last brought up to.
+    fn backup(&mut self, path: &Path, since: Option<u64>) -> Result<u64, Error> {
+        let sequence = self.sequence()?;
+        let mut versions = self.versions(since.unwrap_or(0), sequence)?;
+        let root = versions.roots[LATER].ok_or(Error::RootNotFound)?;
+
+        let mut tree = FileStorage::new(path)?;
+        tree.try_lock()?;
+        let mut wal = FileStorage::new(&default_wal_path(path)?)?;
+        wal.try_lock()?;
+        if since.is_none() {
+            tree.truncate(0)?;
+            wal.truncate(0)?;
+        }
+        let mut wal = Wal::new(wal)?;
+        let mut pager = self.pager.sibling(tree)?;
+        // An incremental backup has to pick up exactly where the backup left off, as logged by
+        // the last commit of the backup.
+        let mut backed_up = None;
+        for record in wal.replay()? {
+            backed_up = change::decode_backup(&pager.open_record(&record)?)?;
+            pager.apply(&record.pages);
+        }
+        if since.is_some() && backed_up != since {
+            return Err(Error::Conflict);
+        }
+
+        // The backup already holds the pages which were reachable as of the commit it was last
+        // brought up to, only those written since have to be copied. Without a page table,
+        // any page which was written since has a new id, so the pages of the later version
+        // which are walked are all of them new.
+        let mut reachable = Vec::new();
+        versions.walk(&mut self.pager, |version, id, _| {
+            if version == LATER {
+                reachable.push(id);
+            }
+        })?;
+        for id in reachable {
+            if self.pager.has_page_table() && !versions.written.contains_key(&id) {
+                continue;
+            }
+            let offset = versions
+                .offset(&self.pager, LATER, &id)
+                .ok_or(Error::UnexpectedError)?;
+            pager.write_page(&id, self.pager.read_page(&id, &offset)?)?;
         }
+        pager.sync()?;
+        let pages = pager.changes();
+        let aad = WalRecord::authenticated_data(wal.sequence() + 1, root, &pages);
+        let metadata = pager.seal_record(change::encode_backup(sequence), &aad)?;
+        wal.commit(root, pages, metadata)?;
+        Ok(sequence)
+    }
+
+    /// write applies a change to the tree given its root, and commits the root it returns.
+    /// If the change fails, the pages it wrote are discarded and the tree is left as it was.
+    /// A change which leaves the root as it was without changing any pair (e.g. deleting
+    /// a key which is absent) commits nothing, so that no empty change set is published.
+    fn write<F>(&mut self, change: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Self, PageId) -> Result<PageId, Error>,
+    {
+        self.check_writable()?;
+        let root_id = self.root()?;
+        let res = change(self, root_id).and_then(|new_root_id| {
+            if new_root_id == root_id && self.changes.is_empty() {
+                self.pager.rollback();
+                return Ok(());
+            }
+            self.commit(new_root_id)
+        });
+        if res.is_err() {
+            self.pager.rollback();
+            self.changes.clear();
+        }
+        res
     }
 
     /// insert a key value pair possibly splitting nodes along the way.
+    /// The pair replaces the pair of the key if any, whether it has expired or not.
     pub fn insert(&mut self, kv: KeyValuePair) -> Result<(), Error> {
-        let root_offset = self.wal.get_root()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        let new_root_offset: Offset;
-        let mut new_root: Node;
-        let mut root = Node::try_from(root_page)?;
-        if self.is_node_full(&root)? {
-            // split the root creating a new root and child nodes along the way.
-            new_root = Node::new(NodeType::Internal(vec![], vec![]), true, None);
-            // write the new root to disk to aquire an offset for the new root.
-            new_root_offset = self.pager.write_page(Page::try_from(&new_root)?)?;
-            // set the old roots parent to the new root.
-            root.parent_offset = Some(new_root_offset.clone());
-            root.is_root = false;
-            // split the old root.
-            let (median, sibling) = root.split(self.b)?;
-            // write the old root with its new data to disk in a *new* location.
-            let old_root_offset = self.pager.write_page(Page::try_from(&root)?)?;
-            // write the newly created sibling to disk.
-            let sibling_offset = self.pager.write_page(Page::try_from(&sibling)?)?;
-            // update the new root with its children and key.
-            new_root.node_type =
-                NodeType::Internal(vec![old_root_offset, sibling_offset], vec![median]);
-            // write the new_root to disk.
-            self.pager
-                .write_page_at_offset(Page::try_from(&new_root)?, &new_root_offset)?;
-        } else {
-            new_root = root.clone();
-            new_root_offset = self.pager.write_page(Page::try_from(&new_root)?)?;
-        }
-        // continue recursively.
-        self.insert_non_full(&mut new_root, new_root_offset.clone(), kv)?;
-        // finish by setting the root to its new copy.
-        self.wal.set_root(new_root_offset)
-    }
-
-    /// insert_non_full (recursively) finds a node rooted at a given non-full node.
-    /// to insert a given key-value pair. Here we assume the node is
-    /// already a copy of an existing node in a copy-on-write root to node traversal.
-    fn insert_non_full(
+        self.write(|btree, root_id| {
+            let key = Key(kv.key);
+            let (path, node_id, mut node) = btree.find_leaf(root_id, &key)?;
+            match &mut node.node_type {
+                NodeType::Leaf(ref mut pairs) => Op::Insert(kv.clone()).apply(pairs)?,
+                _ => return Err(Error::UnexpectedError),
+            }
+            btree.changes.push(Change::Put(kv));
+            btree.rebalance(root_id, path, node_id, node)
+        })
+    }
+
+    /// find_leaf descends from the root to the leaf which holds (or would hold) a given key,
+    /// keeping the path from the root in memory: every ancestor of the leaf alongside the index
+    /// of the child leading to it.
+    #[allow(clippy::type_complexity)]
+    fn find_leaf(
         &mut self,
-        node: &mut Node,
-        node_offset: Offset,
-        kv: KeyValuePair,
-    ) -> Result<(), Error> {
-        match &mut node.node_type {
-            NodeType::Leaf(ref mut pairs) => {
-                let idx = pairs.binary_search(&kv).unwrap_or_else(|x| x);
-                pairs.insert(idx, kv);
-                self.pager
-                    .write_page_at_offset(Page::try_from(&*node)?, &node_offset)
-            }
-            NodeType::Internal(ref mut children, ref mut keys) => {
-                let idx = keys
-                    .binary_search(&Key(kv.key.clone()))
-                    .unwrap_or_else(|x| x);
-                let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
-                let child_page = self.pager.get_page(&child_offset)?;
-                let mut child = Node::try_from(child_page)?;
-                // Copy each branching-node on the root-to-leaf walk.
-                // write_page appends the given page to the db file thus creating a new node.
-                let new_child_offset = self.pager.write_page(Page::try_from(&child)?)?;
-                // Assign copied child at the proper place.
-                children[idx] = new_child_offset.to_owned();
-                if self.is_node_full(&child)? {
-                    // split will split the child at b leaving the [0, b-1] keys
-                    // while moving the set of [b, 2b-1] keys to the sibling.
-                    let (median, mut sibling) = child.split(self.b)?;
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&child)?, &new_child_offset)?;
-                    // Write the newly created sibling to disk.
-                    let sibling_offset = self.pager.write_page(Page::try_from(&sibling)?)?;
-                    // Siblings keys are larger than the splitted child thus need to be inserted
-                    // at the next index.
-                    children.insert(idx + 1, sibling_offset.clone());
-                    keys.insert(idx, median.clone());
-
-                    // Write the parent page to disk.
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
-                    // Continue recursively.
-                    if kv.key <= median.0 {
-                        self.insert_non_full(&mut child, new_child_offset, kv)
-                    } else {
-                        self.insert_non_full(&mut sibling, sibling_offset, kv)
-                    }
-                } else {
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
-                    self.insert_non_full(&mut child, new_child_offset, kv)
+        root_id: PageId,
+        key: &Key,
+    ) -> Result<(Vec<(PageId, Node, usize)>, PageId, Node), Error> {
+        let mut path = Vec::new();
+        let mut node_id = root_id;
+        let mut node = self.get_node(&root_id)?;
+        while let NodeType::Internal(children, keys, _) = &node.node_type {
+            let idx = keys.binary_search(key).unwrap_or_else(|x| x);
+            let child_id = *children.get(idx).ok_or(Error::UnexpectedError)?;
+            let child = self.get_node(&child_id)?;
+            path.push((node_id, node, idx));
+            node_id = child_id;
+            node = child;
+        }
+        Ok((path, node_id, node))
+    }
+
+    /// rebalance writes a node changed at the end of a path from the root, and walks back up
+    /// the path for as long as the change carries over to the parents: an overflowing node is
+    /// split, adding keys to its parent, and an underflowing node is merged with a sibling,
+    /// removing a key from its parent. Only the nodes which change are rewritten, their parents
+    /// keep on referring to them by id, unless they keep subtree counts which have to be
+    /// updated all the way up to the root, or the nodes were given new ids for lack of a page
+    /// table. Returns the id of the root, which changes if the root is split or if it is left
+    /// with a single child, or if it is given a new id.
+    fn rebalance(
+        &mut self,
+        root_id: PageId,
+        mut path: Vec<(PageId, Node, usize)>,
+        mut node_id: PageId,
+        mut node: Node,
+    ) -> Result<PageId, Error> {
+        while let Some((parent_id, mut parent, idx)) = path.pop() {
+            if self.is_node_underflow(&node)? {
+                self.borrow_from_sibling(&mut parent, idx, node, node_id)?;
+            } else {
+                let count = node.count();
+                let (new_id, separators) = self.write_split(node, node_id, vec![])?;
+                if separators.is_empty() && new_id == node_id && !self.counted {
+                    return Ok(root_id);
                 }
+                insert_separators(&mut parent, idx, new_id, count, separators)?;
             }
-            NodeType::Unexpected => Err(Error::UnexpectedError),
+            node_id = parent_id;
+            node = parent;
+        }
+
+        loop {
+            // If merges left the root with a single child, that child becomes the new root.
+            if let NodeType::Internal(children, _, _) = &node.node_type {
+                if children.len() == 1 {
+                    let mut child = self.get_node(&children[0])?;
+                    child.is_root = true;
+                    let child_id = self.pager.relocate(&children[0]);
+                    self.write_node(&child_id, &child)?;
+                    return Ok(child_id);
+                }
+            }
+            // A root which had to be split is placed under a new root.
+            let count = node.count();
+            let (new_id, separators) = self.write_split(node, node_id, vec![])?;
+            if separators.is_empty() {
+                return Ok(new_id);
+            }
+            let counts = match self.counted {
+                true => vec![count.ok_or(Error::UnexpectedError)?],
+                false => Vec::new(),
+            };
+            node = Node::new(NodeType::Internal(vec![new_id], Vec::new(), counts), true);
+            insert_separators(&mut node, 0, new_id, count, separators)?;
+            node_id = self.pager.allocate();
+        }
+    }
+
+    /// write_split writes a node, splitting it in as many nodes as it takes for each of them
+    /// to fit in a page and hold no more than 2b keys. The first node keeps the id of the node,
+    /// the following ones take the given ids and then newly allocated ones, unless the ids of
+    /// committed pages have to be replaced for lack of a page table (see `Pager::relocate`).
+    /// Split leaves are linked in key order if the tree has a page table, the next leaf is
+    /// expected to link back to the last of the given ids (or to the node if none are given).
+    /// Returns the id of the first node, and the nodes following it alongside the keys
+    /// separating them from their predecessors and their counts, which are to be added to
+    /// the parent of the node.
+    #[allow(clippy::type_complexity)]
+    fn write_split(
+        &mut self,
+        node: Node,
+        node_id: PageId,
+        reused_ids: Vec<PageId>,
+    ) -> Result<(PageId, Vec<(Key, PageId, Option<usize>)>), Error> {
+        let linked_id = *reused_ids.last().unwrap_or(&node_id);
+        let node_id = self.pager.relocate(&node_id);
+        let mut parts = vec![node];
+        let mut separators = Vec::new();
+        let mut i = 0;
+        while i < parts.len() {
+            if !self.is_node_overflow(&parts[i])? {
+                i += 1;
+                continue;
+            }
+            // Split around the middle key, both halves are at most as large as the node.
+            let at = match &parts[i].node_type {
+                NodeType::Leaf(pairs) => pairs.len() / 2,
+                NodeType::Internal(_, keys, _) => keys.len() / 2 + 1,
+                NodeType::Unexpected => return Err(Error::UnexpectedError),
+            };
+            let (median, sibling) = parts[i].split(at)?;
+            parts.insert(i + 1, sibling);
+            separators.insert(i, median);
+        }
+
+        let mut reused_ids = reused_ids.into_iter();
+        let mut ids = vec![node_id];
+        for _ in 1..parts.len() {
+            let id = match reused_ids.next() {
+                Some(id) => self.pager.relocate(&id),
+                None => self.pager.allocate(),
+            };
+            ids.push(id);
+        }
+        if parts.len() > 1 {
+            parts[0].is_root = false;
+        }
+        let linked = self.pager.has_page_table();
+        if let (NodeType::Leaf(_), true) = (&parts[0].node_type, linked) {
+            // The first part kept the sibling links of the node.
+            let (prev, next) = (parts[0].prev, parts[0].next);
+            let last = parts.len() - 1;
+            for (i, part) in parts.iter_mut().enumerate() {
+                part.prev = if i == 0 { prev } else { Some(ids[i - 1]) };
+                part.next = if i == last { next } else { Some(ids[i + 1]) };
+            }
+            if let Some(next_id) = next {
+                if ids[last] != linked_id {
+                    let mut next = self.get_node(&next_id)?;
+                    next.prev = Some(ids[last]);
+                    self.write_node(&next_id, &next)?;
+                }
+            }
+        }
+        for (part, id) in parts.iter().zip(&ids) {
+            self.write_node(id, part)?;
         }
+        let separators = separators
+            .into_iter()
+            .zip(ids.into_iter().zip(parts.iter().map(Node::count)).skip(1))
+            .map(|(separator, (id, count))| (separator, id, count))
+            .collect();
+        Ok((node_id, separators))
     }
 
     /// search searches for a specific key in the BTree.
     pub fn search(&mut self, key: &[u8; 16]) -> Result<KeyValuePair, Error> {
-        let root_offset = self.wal.get_root()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        let root = Node::try_from(root_page)?;
-        self.search_node(root, &key)
+        let root_id = self.root()?;
+        let root = self.get_node(&root_id)?;
+        self.search_node(root, key)
     }
 
     /// search_node recursively searches a sub tree rooted at node for a key.
     fn search_node(&mut self, node: Node, search: &[u8; 16]) -> Result<KeyValuePair, Error> {
         match node.node_type {
-            NodeType::Internal(children, keys) => {
-                let idx = keys
-                    .binary_search(&Key(search.clone()))
-                    .unwrap_or_else(|x| x);
+            NodeType::Internal(children, keys, _) => {
+                let idx = keys.binary_search(&Key(*search)).unwrap_or_else(|x| x);
                 // Retrieve child page from disk and deserialize.
-                let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?;
-                let page = self.pager.get_page(child_offset)?;
-                let child_node = Node::try_from(page)?;
+                let child_id = children.get(idx).ok_or(Error::UnexpectedError)?;
+                let child_node = self.get_node(child_id)?;
                 self.search_node(child_node, search)
             }
             NodeType::Leaf(pairs) => {
                 if let Ok(idx) =
-                    pairs.binary_search_by_key(search, |pair| pair.key.clone())
+                    pairs.binary_search_by_key(search, |pair| pair.key)
                 {
-                    return Ok(pairs[idx].clone());
+                    // Expired pairs are as good as deleted until they are purged.
+                    if !pairs[idx].is_expired(now()) {
+                        return Ok(pairs[idx].clone());
+                    }
                 }
                 Err(Error::KeyNotFound)
             }
@@ -229,208 +911,1189 @@ impl BTree {
         }
     }
 
-    /// delete deletes a given key from the tree.
-    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
-        let root_offset = self.wal.get_root()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        // Shadow the new root and rewrite it.
-        let mut new_root = Node::try_from(root_page)?;
-        let new_root_page = Page::try_from(&new_root)?;
-        let new_root_offset = self.pager.write_page(new_root_page)?;
-        self.delete_key_from_subtree(key, &mut new_root, &new_root_offset)?;
-        self.wal.set_root(new_root_offset)
-    }
-
-    /// delete key from subtree recursively traverses a tree rooted at a node in certain offset
-    /// until it finds the given key and delete the key-value pair. Here we assume the node is
-    /// already a copy of an existing node in a copy-on-write root to node traversal.
-    fn delete_key_from_subtree(
+    /// get_many searches for many keys at once, and returns the key-value pair of every key
+    /// which was found or None if it was not, in the order in which the keys were given.
+    /// The keys are searched for in key order, so that the tree is descended from the root once
+    /// for all of them: every node on the way is read at most once.
+    pub fn get_many(&mut self, keys: &[Key]) -> Result<Vec<Option<KeyValuePair>>, Error> {
+        let mut probes: Vec<(usize, &Key)> = keys.iter().enumerate().collect();
+        probes.sort_by_key(|(_, key)| *key);
+        let mut res = vec![None; keys.len()];
+        let root_id = self.root()?;
+        let root = self.get_node(&root_id)?;
+        self.get_many_node(root, &probes, &mut res)?;
+        Ok(res)
+    }
+
+    /// get_many_node searches a sub tree rooted at a node for the given keys, sorted in key order,
+    /// alongside the positions their pairs are to be returned at.
+    fn get_many_node(
         &mut self,
-        key: Key,
-        node: &mut Node,
-        node_offset: &Offset,
+        node: Node,
+        probes: &[(usize, &Key)],
+        res: &mut [Option<KeyValuePair>],
     ) -> Result<(), Error> {
-        match &mut node.node_type {
-            NodeType::Leaf(ref mut pairs) => {
-                let key_idx = pairs
-                    .binary_search_by_key(&key, |kv| Key(kv.key.clone()))
-                    .map_err(|_| Error::KeyNotFound)?;
-                pairs.remove(key_idx);
-                self.pager
-                    .write_page_at_offset(Page::try_from(&*node)?, node_offset)?;
-                // Check for underflow - if it occures,
-                // we need to merge with a sibling.
-                // this can only occur if node is not the root (as it cannot "underflow").
-                // continue recoursively up the tree.
-                self.borrow_if_needed(node.to_owned(), &key)?;
-            }
-            NodeType::Internal(children, keys) => {
-                let node_idx = keys.binary_search(&key).unwrap_or_else(|x| x);
-                // Retrieve child page from disk and deserialize,
-                // copy over the child page and continue recursively.
-                let child_offset = children.get(node_idx).ok_or(Error::UnexpectedError)?;
-                let child_page = self.pager.get_page(child_offset)?;
-                let mut child_node = Node::try_from(child_page)?;
-                // Fix the parent_offset as the child node is a child of a copied parent
-                // in a copy-on-write root to leaf traversal.
-                // This is important for the case of a node underflow which might require a leaf to root traversal.
-                child_node.parent_offset = Some(node_offset.to_owned());
-                let new_child_page = Page::try_from(&child_node)?;
-                let new_child_offset = self.pager.write_page(new_child_page)?;
-                // Assign the new pointer in the parent and continue reccoursively.
-                children[node_idx] = new_child_offset.to_owned();
-                self.pager
-                    .write_page_at_offset(Page::try_from(&*node)?, node_offset)?;
-                return self.delete_key_from_subtree(key, &mut child_node, &new_child_offset);
+        match node.node_type {
+            NodeType::Internal(children, keys, _) => {
+                let mut probes = probes;
+                while let Some((_, key)) = probes.first() {
+                    // The keys leading to the same child follow each other.
+                    let idx = keys.binary_search(key).unwrap_or_else(|x| x);
+                    let len = match keys.get(idx) {
+                        Some(separator) => probes.partition_point(|(_, key)| *key <= separator),
+                        None => probes.len(),
+                    };
+                    let child_id = children.get(idx).ok_or(Error::UnexpectedError)?;
+                    let child = self.get_node(child_id)?;
+                    self.get_many_node(child, &probes[..len], res)?;
+                    probes = &probes[len..];
+                }
+                Ok(())
             }
-            NodeType::Unexpected => return Err(Error::UnexpectedError),
+            NodeType::Leaf(pairs) => {
+                let now = now();
+                for (i, key) in probes {
+                    if let Ok(idx) = pairs.binary_search_by_key(*key, |pair| Key(pair.key)) {
+                        res[*i] = Some(pairs[idx].clone()).filter(|pair| !pair.is_expired(now));
+                    }
+                }
+                Ok(())
+            }
+            NodeType::Unexpected => Err(Error::UnexpectedError),
         }
-        Ok(())
     }
 
-    /// borrow_if_needed checks the node for underflow (following a removal of a key),
-    /// if it underflows it is merged with a sibling node, and than called recoursively
-    /// up the tree. Since the downward root-to-leaf traversal was done using the copy-on-write
-    /// technique we are ensured that any merges will only be reflected in the copied parent in the path.
-    fn borrow_if_needed(&mut self, node: Node, key: &Key) -> Result<(), Error> {
-        if self.is_node_underflow(&node)? {
-            // Fetch the sibling from the parent -
-            // TODO: This could be quicker if we implement sibling pointers.
-            let parent_offset = node.parent_offset.clone().ok_or(Error::UnexpectedError)?;
-            let parent_page = self.pager.get_page(&parent_offset)?;
-            let mut parent_node = Node::try_from(parent_page)?;
-            // The parent has to be an "internal" node.
-            match parent_node.node_type {
-                NodeType::Internal(ref mut children, ref mut keys) => {
-                    let idx = keys.binary_search(key).unwrap_or_else(|x| x);
-                    // The sibling is in idx +- 1 as the above index led
-                    // the downward search to node.
-                    let sibling_idx;
-                    match idx > 0 {
-                        false => sibling_idx = idx + 1,
-                        true => sibling_idx = idx - 1,
-                    }
+    /// first returns the key-value pair with the smallest key in the tree.
+    /// Fails with `Error::KeyNotFound` if the tree is empty, as do the lookups below
+    /// when the tree holds no key matching them.
+    pub fn first(&mut self) -> Result<KeyValuePair, Error> {
+        self.seek_forward(Bound::Unbounded)
+    }
 
-                    let sibling_offset = children.get(sibling_idx).ok_or(Error::UnexpectedError)?;
-                    let sibling_page = self.pager.get_page(sibling_offset)?;
-                    let sibling = Node::try_from(sibling_page)?;
-                    let merged_node = self.merge(node, sibling)?;
-                    let merged_node_offset =
-                        self.pager.write_page(Page::try_from(&merged_node)?)?;
-                    let merged_node_idx = cmp::min(idx, sibling_idx);
-                    // remove the old nodes.
-                    children.remove(merged_node_idx);
-                    // remove shifts nodes to the left.
-                    children.remove(merged_node_idx);
-                    // if the parent is the root, and there is a single child - the merged node -
-                    // we can safely replace the root with the child.
-                    if parent_node.is_root && children.is_empty() {
-                        self.wal.set_root(merged_node_offset)?;
-                        return Ok(());
-                    }
-                    // remove the keys that separated the two nodes from each other:
-                    keys.remove(idx);
-                    // write the new node in place.
-                    children.insert(merged_node_idx, merged_node_offset);
-                    // write the updated parent back to disk and continue up the tree.
-                    self.pager
-                        .write_page_at_offset(Page::try_from(&parent_node)?, &parent_offset)?;
-                    return self.borrow_if_needed(parent_node, key);
-                }
+    /// last returns the key-value pair with the largest key in the tree.
+    pub fn last(&mut self) -> Result<KeyValuePair, Error> {
+        self.seek_backward(Bound::Unbounded)
+    }
+
+    /// floor returns the key-value pair with the largest key smaller than or equal to a given key,
+    /// e.g. the latest event at or before a given time with UUIDv7 keys.
+    pub fn floor(&mut self, key: &Key) -> Result<KeyValuePair, Error> {
+        self.seek_backward(Bound::Included(key))
+    }
+
+    /// ceiling returns the key-value pair with the smallest key larger than or equal to a given key.
+    pub fn ceiling(&mut self, key: &Key) -> Result<KeyValuePair, Error> {
+        self.seek_forward(Bound::Included(key))
+    }
+
+    /// lower returns the key-value pair with the largest key strictly smaller than a given key.
+    pub fn lower(&mut self, key: &Key) -> Result<KeyValuePair, Error> {
+        self.seek_backward(Bound::Excluded(key))
+    }
+
+    /// higher returns the key-value pair with the smallest key strictly larger than a given key.
+    pub fn higher(&mut self, key: &Key) -> Result<KeyValuePair, Error> {
+        self.seek_forward(Bound::Excluded(key))
+    }
+
+    /// seek_forward returns the key-value pair with the smallest key past a given lower bound.
+    /// If no key of the leaf which would hold the bound is past it, the following leaves are
+    /// reached through their sibling links, see `next_leaf`.
+    fn seek_forward(&mut self, start: Bound<&Key>) -> Result<KeyValuePair, Error> {
+        let now = now();
+        let (mut path, mut node) = self.leaf_at(start, false)?;
+        loop {
+            let pairs = match node.node_type {
+                NodeType::Leaf(pairs) => pairs,
                 _ => return Err(Error::UnexpectedError),
+            };
+            let found = pairs.into_iter().find(|pair| {
+                let past_start = match start {
+                    Bound::Included(start) => Key(pair.key) >= *start,
+                    Bound::Excluded(start) => Key(pair.key) > *start,
+                    Bound::Unbounded => true,
+                };
+                past_start && !pair.is_expired(now)
+            });
+            if let Some(pair) = found {
+                return Ok(pair);
+            }
+            match self.next_leaf(&mut path, node.next, true)? {
+                Some(next) => node = next,
+                None => return Err(Error::KeyNotFound),
             }
         }
-        Ok(())
     }
 
-    // merges two *sibling* nodes, it assumes the following:
-    // 1. the two nodes are of the same type.
-    // 2. the two nodes do not accumulate to an overflow,
-    // i.e. |first.keys| + |second.keys| <= [2*(b-1) for keys or 2*b for offsets].
-    fn merge(&self, first: Node, second: Node) -> Result<Node, Error> {
-        match first.node_type {
-            NodeType::Leaf(first_pairs) => {
-                if let NodeType::Leaf(second_pairs) = second.node_type {
-                    let merged_pairs: Vec<KeyValuePair> = first_pairs
-                        .into_iter()
-                        .chain(second_pairs.into_iter())
-                        .collect();
-                    let node_type = NodeType::Leaf(merged_pairs);
-                    Ok(Node::new(node_type, first.is_root, first.parent_offset))
-                } else {
-                    Err(Error::UnexpectedError)
-                }
+    /// seek_backward returns the key-value pair with the largest key short of a given upper
+    /// bound, crossing over to the previous leaves if need be.
+    fn seek_backward(&mut self, end: Bound<&Key>) -> Result<KeyValuePair, Error> {
+        let now = now();
+        let (mut path, mut node) = self.leaf_at(end, true)?;
+        loop {
+            let pairs = match node.node_type {
+                NodeType::Leaf(pairs) => pairs,
+                _ => return Err(Error::UnexpectedError),
+            };
+            let found = pairs.into_iter().rev().find(|pair| {
+                let short_of_end = match end {
+                    Bound::Included(end) => Key(pair.key) <= *end,
+                    Bound::Excluded(end) => Key(pair.key) < *end,
+                    Bound::Unbounded => true,
+                };
+                short_of_end && !pair.is_expired(now)
+            });
+            if let Some(pair) = found {
+                return Ok(pair);
             }
-            NodeType::Internal(first_offsets, first_keys) => {
-                if let NodeType::Internal(second_offsets, second_keys) = second.node_type {
-                    let merged_keys: Vec<Key> = first_keys
-                        .into_iter()
-                        .chain(second_keys.into_iter())
-                        .collect();
-                    let merged_offsets: Vec<Offset> = first_offsets
-                        .into_iter()
-                        .chain(second_offsets.into_iter())
-                        .collect();
-                    let node_type = NodeType::Internal(merged_offsets, merged_keys);
-                    Ok(Node::new(node_type, first.is_root, first.parent_offset))
-                } else {
-                    Err(Error::UnexpectedError)
-                }
+            match self.next_leaf(&mut path, node.prev, false)? {
+                Some(prev) => node = prev,
+                None => return Err(Error::KeyNotFound),
             }
-            NodeType::Unexpected => Err(Error::UnexpectedError),
         }
     }
 
-    /// print_sub_tree is a helper function for recursively printing the nodes rooted at a node given by its offset.
-    fn print_sub_tree(&mut self, prefix: String, offset: Offset) -> Result<(), Error> {
-        println!("{}Node at offset: {}", prefix, offset.0);
-        let curr_prefix = format!("{}|->", prefix);
-        let page = self.pager.get_page(&offset)?;
-        let node = Node::try_from(page)?;
-        match node.node_type {
-            NodeType::Internal(children, keys) => {
-                println!("{}Keys: {:?}", curr_prefix, keys);
-                println!("{}Children: {:?}", curr_prefix, children);
-                let child_prefix = format!("{}   |  ", prefix);
-                for child_offset in children {
-                    self.print_sub_tree(child_prefix.clone(), child_offset)?;
+    /// leaf_at descends from the root to the leaf which holds (or would hold) the key of
+    /// a given bound, or to the leftmost (or rightmost) leaf if the bound is unbounded.
+    /// Returns the leaf alongside the path leading to it: every ancestor of the leaf alongside
+    /// the index of the child leading to it.
+    #[allow(clippy::type_complexity)]
+    fn leaf_at(
+        &mut self,
+        bound: Bound<&Key>,
+        rightmost: bool,
+    ) -> Result<(Vec<(Node, usize)>, Node), Error> {
+        let root_id = self.root()?;
+        let mut path = Vec::new();
+        let mut node = self.get_node(&root_id)?;
+        while let NodeType::Internal(children, keys, _) = &node.node_type {
+            let idx = match bound {
+                Bound::Included(key) | Bound::Excluded(key) => {
+                    keys.binary_search(key).unwrap_or_else(|x| x)
                 }
-                Ok(())
-            }
-            NodeType::Leaf(pairs) => {
-                println!("{}Key value pairs: {:?}", curr_prefix, pairs);
-                Ok(())
+                Bound::Unbounded if rightmost => children.len() - 1,
+                Bound::Unbounded => 0,
+            };
+            let child_id = *children.get(idx).ok_or(Error::UnexpectedError)?;
+            let child = self.get_node(&child_id)?;
+            path.push((node, idx));
+            node = child;
+        }
+        Ok((path, node))
+    }
+
+    /// next_leaf returns the leaf following (or preceding) a leaf in key order, if any, given
+    /// the path leading to the leaf (see `leaf_at`) and its sibling link in that direction.
+    /// Leaves are linked in trees with a page table. In any other tree, the path is walked
+    /// back up to the first ancestor with a child further along, which is descended from
+    /// instead, the path being kept up to date along the way.
+    fn next_leaf(
+        &mut self,
+        path: &mut Vec<(Node, usize)>,
+        sibling: Option<PageId>,
+        forward: bool,
+    ) -> Result<Option<Node>, Error> {
+        if self.pager.has_page_table() {
+            return sibling.map(|id| self.get_node(&id)).transpose();
+        }
+        while let Some((parent, idx)) = path.pop() {
+            let children = match &parent.node_type {
+                NodeType::Internal(children, _, _) => children,
+                _ => return Err(Error::UnexpectedError),
+            };
+            let idx = match forward {
+                true => idx + 1,
+                false => match idx.checked_sub(1) {
+                    Some(idx) => idx,
+                    None => continue,
+                },
+            };
+            let mut child_id = match children.get(idx) {
+                Some(child_id) => *child_id,
+                None => continue,
+            };
+            path.push((parent, idx));
+            loop {
+                let node = self.get_node(&child_id)?;
+                let children = match &node.node_type {
+                    NodeType::Internal(children, _, _) => children,
+                    _ => return Ok(Some(node)),
+                };
+                let idx = match forward {
+                    true => 0,
+                    false => children
+                        .len()
+                        .checked_sub(1)
+                        .ok_or(Error::UnexpectedError)?,
+                };
+                child_id = children[idx];
+                path.push((node, idx));
             }
-            NodeType::Unexpected => Err(Error::UnexpectedError),
         }
+        Ok(None)
     }
 
-    /// print is a helper for recursively printing the tree.
-    pub fn print(&mut self) -> Result<(), Error> {
-        println!();
-        let root_offset = self.wal.get_root()?;
-        self.print_sub_tree("".to_string(), root_offset)
+    /// range returns the key-value pairs whose keys fall within a given range, in key order.
+    /// Only the leaf holding the start of the range is searched for from the root,
+    /// the following leaves are reached through their sibling links, or along the path from
+    /// the root in trees without a page table.
+    pub fn range(&mut self, range: impl RangeBounds<Key>) -> Result<Vec<KeyValuePair>, Error> {
+        let now = now();
+        let mut res = Vec::new();
+        self.for_each_in_range(range, |pair| {
+            if !pair.is_expired(now) {
+                res.push(pair)
+            }
+        })?;
+        Ok(res)
     }
-}
 
-#[cfg(test)]
+    /// scan_prefix visits the key-value pairs whose keys start with a given prefix, in key order,
+    /// e.g. every key of a tenant whose id makes up the first bytes of its keys.
+    /// Pairs are handed to the visitor leaf by leaf, so that no more than a leaf is held at once.
+    pub fn scan_prefix<F>(&mut self, prefix: &[u8], mut visit: F) -> Result<(), Error>
+    where
+        F: FnMut(KeyValuePair),
+    {
+        let now = now();
+        self.for_each_in_range(prefix_range(prefix)?, |pair| {
+            if !pair.is_expired(now) {
+                visit(pair)
+            }
+        })
+    }
+
+    /// count_prefix returns the number of keys which start with a given prefix.
+    /// Like `count_range`, and unlike `scan_prefix`, it counts expired pairs until they are purged.
+    /// A tree which keeps subtree counts answers with two descents from the root, any other tree
+    /// counts the keys of the leaves in range only, rather than every leaf before the range.
+    pub fn count_prefix(&mut self, prefix: &[u8]) -> Result<usize, Error> {
+        let range = prefix_range(prefix)?;
+        let root_id = self.root()?;
+        if self.get_node(&root_id)?.count().is_some() {
+            return self.count_range(range);
+        }
+        let mut count = 0;
+        self.for_each_in_range(range, |_| count += 1)?;
+        Ok(count)
+    }
+
+    /// len returns the number of keys held in the tree.
+    /// Like the other order statistics, it takes a single descent from the root in a tree which
+    /// keeps subtree counts, and falls back to counting the keys leaf by leaf otherwise.
+    /// Expired pairs are counted until they are purged.
+    pub fn len(&mut self) -> Result<usize, Error> {
+        let root_id = self.root()?;
+        self.count_subtree(&root_id)
+    }
+
+    /// is_empty returns whether the tree holds no keys at all.
+    pub fn is_empty(&mut self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// count_range returns the number of keys which fall within a given range.
+    /// Expired pairs are counted until they are purged, as subtree counts cannot tell them apart.
+    pub fn count_range(&mut self, range: impl RangeBounds<Key>) -> Result<usize, Error> {
+        let root_id = self.root()?;
+        let below_end = match range.end_bound() {
+            Bound::Included(end) => self.count_below(root_id, end, true)?,
+            Bound::Excluded(end) => self.count_below(root_id, end, false)?,
+            Bound::Unbounded => self.count_subtree(&root_id)?,
+        };
+        let below_start = match range.start_bound() {
+            Bound::Included(start) => self.count_below(root_id, start, false)?,
+            Bound::Excluded(start) => self.count_below(root_id, start, true)?,
+            Bound::Unbounded => 0,
+        };
+        Ok(below_end.saturating_sub(below_start))
+    }
+
+    /// rank returns the number of keys smaller than a given key, which is the position
+    /// of the key in key order if the tree holds it. Expired pairs are counted until they are purged.
+    pub fn rank(&mut self, key: &Key) -> Result<usize, Error> {
+        let root_id = self.root()?;
+        self.count_below(root_id, key, false)
+    }
+
+    /// select returns the k-th key-value pair in key order, counting from zero.
+    /// Fails with `Error::KeyNotFound` if the tree holds no more than k keys.
+    /// Expired pairs keep their positions until they are purged, and are returned like any
+    /// other pair: unlike searches and scans, select has to agree with `rank` and `len`.
+    pub fn select(&mut self, k: usize) -> Result<KeyValuePair, Error> {
+        let root_id = self.root()?;
+        let mut node = self.get_node(&root_id)?;
+        let mut k = k;
+        loop {
+            match node.node_type {
+                NodeType::Internal(children, _, counts) => {
+                    // Skip the children holding the first k keys.
+                    let counts = self.child_counts(&children, &counts)?;
+                    let mut idx = 0;
+                    while idx < counts.len() && k >= counts[idx] {
+                        k -= counts[idx];
+                        idx += 1;
+                    }
+                    let child_id = children.get(idx).ok_or(Error::KeyNotFound)?;
+                    node = self.get_node(child_id)?;
+                }
+                NodeType::Leaf(pairs) => {
+                    return pairs.into_iter().nth(k).ok_or(Error::KeyNotFound);
+                }
+                NodeType::Unexpected => return Err(Error::UnexpectedError),
+            }
+        }
+    }
+
+    /// count_below returns the number of keys smaller than a given key (or equal to it
+    /// if inclusive) in the sub tree rooted at a node given by its id.
+    fn count_below(&mut self, id: PageId, key: &Key, inclusive: bool) -> Result<usize, Error> {
+        let mut node = self.get_node(&id)?;
+        let mut count = 0;
+        loop {
+            match node.node_type {
+                NodeType::Internal(children, keys, counts) => {
+                    // The keys held under the children preceding the child which would
+                    // hold the key are all smaller than it.
+                    let idx = keys.binary_search(key).unwrap_or_else(|x| x);
+                    let preceding = counts.get(..idx).unwrap_or(&[]);
+                    count += self
+                        .child_counts(&children[..idx], preceding)?
+                        .iter()
+                        .sum::<usize>();
+                    let child_id = children.get(idx).ok_or(Error::UnexpectedError)?;
+                    node = self.get_node(child_id)?;
+                }
+                NodeType::Leaf(pairs) => {
+                    let below = pairs.partition_point(|pair| match inclusive {
+                        true => Key(pair.key) <= *key,
+                        false => Key(pair.key) < *key,
+                    });
+                    return Ok(count + below);
+                }
+                NodeType::Unexpected => return Err(Error::UnexpectedError),
+            }
+        }
+    }
+
+    /// child_counts returns the number of pairs held under each of the given children
+    /// of an internal node, given the subtree counts the node keeps for them if any.
+    fn child_counts(&mut self, children: &[PageId], counts: &[usize]) -> Result<Vec<usize>, Error> {
+        if !counts.is_empty() {
+            return Ok(counts.to_vec());
+        }
+        children.iter().map(|id| self.count_subtree(id)).collect()
+    }
+
+    /// count_subtree returns the number of pairs held under a node given by its id.
+    fn count_subtree(&mut self, id: &PageId) -> Result<usize, Error> {
+        let node = self.get_node(id)?;
+        if let Some(count) = node.count() {
+            return Ok(count);
+        }
+        match node.node_type {
+            NodeType::Internal(children, _, _) => {
+                Ok(self.child_counts(&children, &[])?.iter().sum())
+            }
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// for_each_in_range visits the key-value pairs whose keys fall within a given range,
+    /// expired or not, in key order, and stops as soon as it walks past the end of the range.
+    fn for_each_in_range<F>(
+        &mut self,
+        range: impl RangeBounds<Key>,
+        mut visit: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(KeyValuePair),
+    {
+        // Descend to the leftmost leaf which might hold keys in range.
+        let (mut path, mut node) = self.leaf_at(range.start_bound(), false)?;
+        loop {
+            let pairs = match node.node_type {
+                NodeType::Leaf(pairs) => pairs,
+                _ => return Err(Error::UnexpectedError),
+            };
+            for pair in pairs {
+                let key = Key(pair.key);
+                let past_end = match range.end_bound() {
+                    Bound::Included(end) => key > *end,
+                    Bound::Excluded(end) => key >= *end,
+                    Bound::Unbounded => false,
+                };
+                if past_end {
+                    return Ok(());
+                }
+                if range.contains(&key) {
+                    visit(pair);
+                }
+            }
+            match self.next_leaf(&mut path, node.next, true)? {
+                Some(next) => node = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// delete deletes a given key from the tree.
+    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
+        self.write(|btree, root_id| {
+            let (path, node_id, mut node) = btree.find_leaf(root_id, &key)?;
+            match &mut node.node_type {
+                NodeType::Leaf(ref mut pairs) => Op::Delete(key.clone()).apply(pairs)?,
+                _ => return Err(Error::UnexpectedError),
+            }
+            btree.changes.push(Change::Delete(key));
+            btree.rebalance(root_id, path, node_id, node)
+        })
+    }
+
+    /// compare_and_swap sets the value of a key, or deletes the key if there is no new value,
+    /// provided that the key holds the expected value, or that it is absent if no value is
+    /// expected. Otherwise it fails with `Error::Conflict`, leaving the tree untouched.
+    /// The value is checked and written within the same write, against the same root.
+    /// A swapped value keeps the expiry time of the value it replaces.
+    pub fn compare_and_swap(
+        &mut self,
+        key: Key,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<(), Error> {
+        self.write(|btree, root_id| {
+            let (path, node_id, mut node) = btree.find_leaf(root_id, &key)?;
+            let pairs = match &mut node.node_type {
+                NodeType::Leaf(ref mut pairs) => pairs,
+                _ => return Err(Error::UnexpectedError),
+            };
+            let found = pairs.binary_search_by_key(&key, |kv| Key(kv.key));
+            // Expired pairs are as good as absent.
+            let live = match found {
+                Ok(idx) if !pairs[idx].is_expired(now()) => Some(&pairs[idx]),
+                _ => None,
+            };
+            if live.map(|kv| kv.value.as_str()) != expected.as_deref() {
+                return Err(Error::Conflict);
+            }
+            let expires_at = live.and_then(|kv| kv.expires_at);
+            let change = match (found, new) {
+                (Ok(idx), Some(value)) => {
+                    pairs[idx] = KeyValuePair {
+                        key: key.0,
+                        value,
+                        expires_at,
+                    };
+                    Change::Put(pairs[idx].clone())
+                }
+                (Err(idx), Some(value)) => {
+                    pairs.insert(idx, KeyValuePair::new(key.0, value));
+                    Change::Put(pairs[idx].clone())
+                }
+                (Ok(idx), None) => {
+                    pairs.remove(idx);
+                    Change::Delete(key)
+                }
+                (Err(_), None) => return Ok(root_id),
+            };
+            btree.changes.push(change);
+            btree.rebalance(root_id, path, node_id, node)
+        })
+    }
+
+    /// compare_and_delete deletes a key provided that it holds the expected value,
+    /// and fails with `Error::Conflict` otherwise, see `compare_and_swap`.
+    pub fn compare_and_delete(&mut self, key: Key, expected: String) -> Result<(), Error> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// apply_batch applies many writes at once, as a single commit: either all of them are
+    /// applied or, if any of them fails, none of them are. Writes to the same key are applied
+    /// in the order in which they are given.
+    ///
+    /// The writes are sorted by key and applied leaf by leaf, the writes to a leaf all at once
+    /// before the leaf is split or merged and its path rebalanced. As every node is only copied
+    /// the first time it is written within a commit, every node the batch touches is copied
+    /// once however many writes it takes, and only a single new root is committed.
+    pub fn apply_batch(&mut self, ops: Vec<Op>) -> Result<(), Error> {
+        let mut ops = ops;
+        ops.sort_by_key(Op::key);
+        self.write(|btree, root_id| {
+            let mut root_id = root_id;
+            let mut ops = ops.into_iter().peekable();
+            while let Some(op) = ops.peek() {
+                let (path, node_id, mut node) = btree.find_leaf(root_id, &op.key())?;
+                // The leaf holds the keys up to the first key of an ancestor past it, if any.
+                let end = path
+                    .iter()
+                    .rev()
+                    .find_map(|(_, parent, idx)| match &parent.node_type {
+                        NodeType::Internal(_, keys, _) => keys.get(*idx).cloned(),
+                        _ => None,
+                    });
+                let pairs = match &mut node.node_type {
+                    NodeType::Leaf(ref mut pairs) => pairs,
+                    _ => return Err(Error::UnexpectedError),
+                };
+                while let Some(op) =
+                    ops.next_if(|op| end.as_ref().is_none_or(|end| op.key() <= *end))
+                {
+                    btree.changes.push(op.change());
+                    op.apply(pairs)?;
+                }
+                root_id = btree.rebalance(root_id, path, node_id, node)?;
+            }
+            Ok(root_id)
+        })
+    }
+
+    /// delete_range deletes every key within a given range from the tree, e.g. every key older
+    /// than a cutoff with UUIDv7 keys.
+    ///
+    /// Only the nodes straddling either end of the range are read and trimmed: the sub trees
+    /// which fall entirely within the range are dropped whole, without visiting their leaves.
+    /// The leaves on either side of the range are then linked to each other (if the tree has
+    /// a page table), and the tree is rebalanced once along both ends of the range.
+    pub fn delete_range(&mut self, range: impl RangeBounds<Key>) -> Result<(), Error> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.write(|btree, root_id| {
+            // An empty range deletes nothing, and the leaves bounding a reversed one
+            // must not be linked to each other.
+            if is_empty_range(&range) {
+                return Ok(root_id);
+            }
+            btree
+                .changes
+                .push(Change::DeleteRange(range.0.clone(), range.1.clone()));
+            let root = btree.get_node(&root_id)?;
+            let (root_id, _) = btree.delete_range_node(root_id, root, &range, None, None)?;
+
+            let start = bound_key(&range.0, 0x00);
+            let end = bound_key(&range.1, 0xFF);
+            let mut first = match range.0 {
+                Bound::Unbounded => None,
+                _ => btree
+                    .find_leaf(root_id, &start)
+                    .map(|(_, id, leaf)| Some((id, leaf)))?,
+            };
+            let mut last = match range.1 {
+                Bound::Unbounded => None,
+                _ => btree
+                    .find_leaf(root_id, &end)
+                    .map(|(_, id, leaf)| Some((id, leaf)))?,
+            };
+            let first_id = first.as_ref().map(|(id, _)| *id);
+            let last_id = last.as_ref().map(|(id, _)| *id);
+            if first_id != last_id && btree.pager.has_page_table() {
+                if let Some((id, leaf)) = &mut first {
+                    leaf.next = last_id;
+                    btree.write_node(id, leaf)?;
+                }
+                if let Some((id, leaf)) = &mut last {
+                    leaf.prev = first_id;
+                    btree.write_node(id, leaf)?;
+                }
+            }
+
+            let root_id = btree.settle(root_id, &start)?;
+            btree.settle(root_id, &end)
+        })
+    }
+
+    /// purge_expired deletes every pair which has expired, as a single batch, and returns
+    /// the number of pairs deleted. Every leaf of the tree is read to find them, see
+    /// `purge_keys_older_than` for trees whose pairs expire in key order.
+    pub fn purge_expired(&mut self) -> Result<usize, Error> {
+        let now = now();
+        let mut ops = Vec::new();
+        self.for_each_in_range(.., |pair| {
+            if pair.is_expired(now) {
+                ops.push(Op::Delete(Key(pair.key)))
+            }
+        })?;
+        let purged = ops.len();
+        if purged > 0 {
+            self.apply_batch(ops)?;
+        }
+        Ok(purged)
+    }
+
+    /// purge_keys_older_than deletes every pair whose UUIDv7 key was generated more than
+    /// a given time ago. UUIDv7 keys start with the time they were generated at, in
+    /// milliseconds since the Unix epoch, so that giving all pairs the same time to live
+    /// expires them in key order: they are all deleted by a single range delete, which drops
+    /// whole sub trees without reading their leaves rather than scanning the tree.
+    /// Expiry times stored alongside the pairs play no part in it.
+    ///
+    /// Every key of the tree must be a UUIDv7: keys are never read one by one, so the version
+    /// of a key is not checked, and any other key which sorts before the cutoff is deleted too.
+    pub fn purge_keys_older_than(&mut self, ttl: Duration) -> Result<(), Error> {
+        let cutoff = now().saturating_sub(ttl.as_millis() as u64);
+        let mut key = [0x00; KEY_SIZE];
+        key[..UUID_V7_TIMESTAMP_SIZE].copy_from_slice(&cutoff.to_be_bytes()[2..]);
+        self.delete_range(..Key(key))
+    }
+
+    /// delete_range_node deletes the keys within a range from the sub tree rooted at a node,
+    /// given the keys bounding the sub tree from below (exclusive) and above (inclusive) if any.
+    /// Children falling entirely within the range are dropped without being read, children
+    /// falling entirely out of it are left as they are, and the others are descended into.
+    /// Returns the id what is left of the node was written under (see `Pager::relocate`) and
+    /// its count. The node is written as is: it is up to the caller to relink the leaves and
+    /// to rebalance the tree.
+    fn delete_range_node(
+        &mut self,
+        node_id: PageId,
+        mut node: Node,
+        range: &(Bound<Key>, Bound<Key>),
+        lower: Option<&Key>,
+        upper: Option<&Key>,
+    ) -> Result<(PageId, Option<usize>), Error> {
+        let node_id = self.pager.relocate(&node_id);
+        let (children, keys, counts) = match node.node_type {
+            NodeType::Internal(children, keys, counts) => (children, keys, counts),
+            NodeType::Leaf(ref mut pairs) => {
+                pairs.retain(|pair| !range.contains(&Key(pair.key)));
+                self.write_node(&node_id, &node)?;
+                return Ok((node_id, node.count()));
+            }
+            NodeType::Unexpected => return Err(Error::UnexpectedError),
+        };
+
+        // The children which are left alongside the key bounding them from above and their count.
+        let mut kept = Vec::new();
+        for (i, child_id) in children.into_iter().enumerate() {
+            let lo = if i == 0 { lower } else { keys.get(i - 1) };
+            let hi = keys.get(i).or(upper);
+            let before_start = match (&range.0, hi) {
+                (Bound::Included(start), Some(hi)) => hi < start,
+                (Bound::Excluded(start), Some(hi)) => hi <= start,
+                _ => false,
+            };
+            let after_end = match (&range.1, lo) {
+                (Bound::Included(end) | Bound::Excluded(end), Some(lo)) => lo >= end,
+                _ => false,
+            };
+            let from_start = match (&range.0, lo) {
+                (Bound::Unbounded, _) => true,
+                (Bound::Included(start) | Bound::Excluded(start), Some(lo)) => lo >= start,
+                _ => false,
+            };
+            let to_end = match (&range.1, hi) {
+                (Bound::Unbounded, _) => true,
+                (Bound::Included(end), Some(hi)) => hi <= end,
+                (Bound::Excluded(end), Some(hi)) => hi < end,
+                _ => false,
+            };
+            if before_start || after_end {
+                kept.push((child_id, hi.cloned(), counts.get(i).cloned()));
+            } else if !(from_start && to_end) {
+                let child = self.get_node(&child_id)?;
+                let (child_id, count) = self.delete_range_node(child_id, child, range, lo, hi)?;
+                kept.push((child_id, hi.cloned(), count));
+            }
+        }
+
+        // A root whose children all fall within the range is left empty.
+        if kept.is_empty() {
+            let root = Node::new(NodeType::Leaf(vec![]), node.is_root);
+            self.write_node(&node_id, &root)?;
+            return Ok((node_id, root.count()));
+        }
+        // The children which were dropped held no keys that are left, so every child which is
+        // left is separated from the next one by the key bounding it from above.
+        let num_keys = kept.len() - 1;
+        let mut new_children = Vec::new();
+        let mut new_keys = Vec::new();
+        let mut new_counts = Vec::new();
+        for (i, (child_id, hi, count)) in kept.into_iter().enumerate() {
+            new_children.push(child_id);
+            if i < num_keys {
+                new_keys.push(hi.ok_or(Error::UnexpectedError)?);
+            }
+            if !counts.is_empty() {
+                new_counts.push(count.ok_or(Error::UnexpectedError)?);
+            }
+        }
+        node.node_type = NodeType::Internal(new_children, new_keys, new_counts);
+        self.write_node(&node_id, &node)?;
+        Ok((node_id, node.count()))
+    }
+
+    /// settle rebalances the nodes on the path from the root to the leaf which holds (or would
+    /// hold) a given key, from the leaf up, whether they changed or not: underflowing nodes are
+    /// merged with a sibling and overflowing ones are split. A node left with a single child
+    /// is merged as a whole by its own parent, after which the child might underflow next to
+    /// its new siblings, so the path is walked again until no node is merged along the way.
+    /// Returns the id of the root.
+    fn settle(&mut self, root_id: PageId, key: &Key) -> Result<PageId, Error> {
+        let mut root_id = root_id;
+        loop {
+            let (mut path, mut node_id, mut node) = self.find_leaf(root_id, key)?;
+            let mut merged = false;
+            while let Some((parent_id, mut parent, idx)) = path.pop() {
+                let has_sibling = match &parent.node_type {
+                    NodeType::Internal(children, _, _) => children.len() > 1,
+                    _ => return Err(Error::UnexpectedError),
+                };
+                if has_sibling && self.is_node_underflow(&node)? {
+                    self.borrow_from_sibling(&mut parent, idx, node, node_id)?;
+                    merged = true;
+                } else {
+                    let count = node.count();
+                    let (new_id, separators) = self.write_split(node, node_id, vec![])?;
+                    insert_separators(&mut parent, idx, new_id, count, separators)?;
+                }
+                node_id = parent_id;
+                node = parent;
+            }
+            let new_root_id = self.rebalance(node_id, vec![], node_id, node)?;
+            if !merged && new_root_id == root_id {
+                return Ok(root_id);
+            }
+            root_id = new_root_id;
+        }
+    }
+
+    /// borrow_from_sibling merges an underflowing node with a sibling node given the parent
+    /// of both and the index of the node within the parent. If the merged node overflows it is
+    /// split again, effectively borrowing keys from the sibling. The parent loses a key
+    /// unless keys were borrowed, and is updated with the ids the nodes were written under,
+    /// it is up to the caller to write it and to rebalance it.
+    fn borrow_from_sibling(
+        &mut self,
+        parent: &mut Node,
+        idx: usize,
+        node: Node,
+        node_id: PageId,
+    ) -> Result<(), Error> {
+        // The parent has to be an "internal" node.
+        let (children, keys, counts) = match parent.node_type {
+            NodeType::Internal(ref mut children, ref mut keys, ref mut counts) => {
+                (children, keys, counts)
+            }
+            _ => return Err(Error::UnexpectedError),
+        };
+        // The sibling is in idx +- 1. Sibling links are of no use here,
+        // as the neighbouring leaf might belong to another parent.
+        let sibling_idx = match idx > 0 {
+            false => idx + 1,
+            true => idx - 1,
+        };
+
+        let sibling_id = *children.get(sibling_idx).ok_or(Error::UnexpectedError)?;
+        let sibling = self.get_node(&sibling_id)?;
+        // Merge the two nodes in key order around the key that separates them.
+        let merged_node_idx = cmp::min(idx, sibling_idx);
+        let separator = keys.remove(merged_node_idx);
+        children.remove(merged_node_idx + 1);
+        if !counts.is_empty() {
+            counts.remove(merged_node_idx + 1);
+        }
+        let (merged_node, first_id, second_id) = match idx < sibling_idx {
+            true => (self.merge(node, sibling, separator)?, node_id, sibling_id),
+            false => (self.merge(sibling, node, separator)?, sibling_id, node_id),
+        };
+        // The merged node takes the place of the first node. If the sibling had keys to spare
+        // the merged node is split again, reusing the id of the second node.
+        let count = merged_node.count();
+        let (merged_id, separators) = self.write_split(merged_node, first_id, vec![second_id])?;
+        insert_separators(parent, merged_node_idx, merged_id, count, separators)
+    }
+
+    /// is_node_overflow checks whether a node holds more keys than it is allowed to,
+    /// or more than fit in a page.
+    fn is_node_overflow(&self, node: &Node) -> Result<bool, Error> {
+        let many_keys = match &node.node_type {
+            NodeType::Leaf(pairs) => pairs.len() > (2 * self.b),
+            NodeType::Internal(_, keys, _) => keys.len() > (2 * self.b - 1),
+            NodeType::Unexpected => return Err(Error::UnexpectedError),
+        };
+        Ok(many_keys || Page::encoded_len(node)? > PAGE_SIZE)
+    }
+
+    // merges two *sibling* nodes, it assumes the following:
+    // 1. the two nodes are of the same type.
+    // 2. all of the keys of first are smaller than separator,
+    // which in turn is smaller than all of the keys of second.
+    // The separator is pulled down into merged internal nodes, leaves have no use for it.
+    // A merged leaf is linked to the previous leaf of first and to the next leaf of second.
+    // The merged node might overflow, in which case it is up to the caller to split it.
+    fn merge(&self, first: Node, second: Node, separator: Key) -> Result<Node, Error> {
+        match first.node_type {
+            NodeType::Leaf(first_pairs) => {
+                if let NodeType::Leaf(second_pairs) = second.node_type {
+                    let merged_pairs: Vec<KeyValuePair> =
+                        first_pairs.into_iter().chain(second_pairs).collect();
+                    let node_type = NodeType::Leaf(merged_pairs);
+                    let mut merged = Node::new(node_type, first.is_root);
+                    merged.prev = first.prev;
+                    merged.next = second.next;
+                    Ok(merged)
+                } else {
+                    Err(Error::UnexpectedError)
+                }
+            }
+            NodeType::Internal(first_children, first_keys, first_counts) => {
+                if let NodeType::Internal(second_children, second_keys, second_counts) =
+                    second.node_type
+                {
+                    let merged_keys: Vec<Key> = first_keys
+                        .into_iter()
+                        .chain(std::iter::once(separator))
+                        .chain(second_keys)
+                        .collect();
+                    let merged_children: Vec<PageId> =
+                        first_children.into_iter().chain(second_children).collect();
+                    let merged_counts: Vec<usize> =
+                        first_counts.into_iter().chain(second_counts).collect();
+                    let node_type = NodeType::Internal(merged_children, merged_keys, merged_counts);
+                    Ok(Node::new(node_type, first.is_root))
+                } else {
+                    Err(Error::UnexpectedError)
+                }
+            }
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// print_sub_tree is a helper function for recursively printing the nodes rooted at a node given by its id.
+    fn print_sub_tree(&mut self, prefix: String, id: PageId) -> Result<(), Error> {
+        println!("{}Node with id: {}", prefix, id.0);
+        let curr_prefix = format!("{}|->", prefix);
+        let node = self.get_node(&id)?;
+        match node.node_type {
+            NodeType::Internal(children, keys, _) => {
+                println!("{}Keys: {:?}", curr_prefix, keys);
+                println!("{}Children: {:?}", curr_prefix, children);
+                let child_prefix = format!("{}   |  ", prefix);
+                for child_id in children {
+                    self.print_sub_tree(child_prefix.clone(), child_id)?;
+                }
+                Ok(())
+            }
+            NodeType::Leaf(pairs) => {
+                println!("{}Key value pairs: {:?}", curr_prefix, pairs);
+                Ok(())
+            }
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// print is a helper for recursively printing the tree.
+    pub fn print(&mut self) -> Result<(), Error> {
+        println!();
+        let root_id = self.root()?;
+        self.print_sub_tree("".to_string(), root_id)
+    }
+}
+
+/// Versions finds the pages of two versions of the tree, named by the sequence numbers of their
+/// commits, given the WAL records from the earlier commit on (see `BTree::versions`).
+///
+/// A page which was not written in between is found at the same offset in both versions, which
+/// is its offset in the page table unless it was written after the later version too. Every WAL
+/// record lists the offsets of the copies its pages replace, so the offsets of the pages which
+/// were written in between, or after, are read off the first record writing them.
+#[derive(Default)]
+struct Versions {
+    /// The root of either version, there is no root as of sequence number zero.
+    roots: [Option<PageId>; 2],
+    /// The offsets of the pages written in between as of either version, if they belong to it.
+    written: HashMap<PageId, [Option<Offset>; 2]>,
+    /// The offsets as of the later version of the pages written after it, if they belong to it.
+    rewritten: HashMap<PageId, Option<Offset>>,
+    /// The nodes read so far by their ids and offsets, so that no page is read twice.
+    nodes: HashMap<(PageId, usize), Node>,
+}
+
+impl Versions {
+    /// offset returns the offset of a page as of either version, or None if the version
+    /// has no such page.
+    fn offset<S: Storage>(&self, pager: &Pager<S>, version: usize, id: &PageId) -> Option<Offset> {
+        if let Some(offsets) = self.written.get(id) {
+            return offsets[version].clone();
+        }
+        if let Some(offset) = self.rewritten.get(id) {
+            return offset.clone();
+        }
+        pager.offset(id)
+    }
+
+    /// node reads the node with a given id as of either version.
+    fn node<S: Storage>(
+        &mut self,
+        pager: &mut Pager<S>,
+        version: usize,
+        id: &PageId,
+    ) -> Result<Node, Error> {
+        let offset = self
+            .offset(pager, version, id)
+            .ok_or(Error::UnexpectedError)?;
+        if let Some(node) = self.nodes.get(&(*id, offset.0)) {
+            return Ok(node.clone());
+        }
+        let node = Node::try_from(pager.read_page(id, &offset)?)?;
+        self.nodes.insert((*id, offset.0), node.clone());
+        Ok(node)
+    }
+
+    /// touched returns the ids of the pages written in between, and of their ancestors in
+    /// either version: any other page roots the same sub tree in both versions. Each written
+    /// page is found by descending from the root along one of its own keys, which does not work
+    /// for an empty page other than a root, in which case None is returned.
+    fn touched<S: Storage>(
+        &mut self,
+        pager: &mut Pager<S>,
+    ) -> Result<Option<HashSet<PageId>>, Error> {
+        // Every page of a version following the empty tree was written in between.
+        if self.roots[EARLIER].is_none() {
+            return Ok(None);
+        }
+        let written: Vec<PageId> = self.written.keys().cloned().collect();
+        let mut touched: HashSet<PageId> = written.iter().cloned().collect();
+        for id in written {
+            for version in [EARLIER, LATER] {
+                if self.offset(pager, version, &id).is_none() {
+                    continue;
+                }
+                let node = self.node(pager, version, &id)?;
+                let key = match &node.node_type {
+                    NodeType::Leaf(pairs) => pairs.first().map(|pair| Key(pair.key)),
+                    NodeType::Internal(_, keys, _) => keys.first().cloned(),
+                    NodeType::Unexpected => return Err(Error::UnexpectedError),
+                };
+                let key = match key {
+                    Some(key) => key,
+                    None if node.is_root => continue,
+                    None => return Ok(None),
+                };
+                let mut ancestor = self.roots[version].ok_or(Error::UnexpectedError)?;
+                while ancestor != id {
+                    touched.insert(ancestor);
+                    match self.node(pager, version, &ancestor)?.node_type {
+                        NodeType::Internal(children, keys, _) => {
+                            let idx = keys.binary_search(&key).unwrap_or_else(|x| x);
+                            ancestor = *children.get(idx).ok_or(Error::UnexpectedError)?;
+                        }
+                        // The page is no longer reachable from the root.
+                        _ => break,
+                    }
+                }
+            }
+        }
+        Ok(Some(touched))
+    }
+
+    /// height returns the number of levels of internal nodes above the leaves of a sub tree.
+    fn height<S: Storage>(
+        &mut self,
+        pager: &mut Pager<S>,
+        version: usize,
+        id: PageId,
+    ) -> Result<usize, Error> {
+        let mut height = 0;
+        let mut id = id;
+        while let NodeType::Internal(children, _, _) = self.node(pager, version, &id)?.node_type {
+            id = *children.first().ok_or(Error::UnexpectedError)?;
+            height += 1;
+        }
+        Ok(height)
+    }
+
+    /// walk visits the nodes of both versions from their roots down, but for the sub trees
+    /// both versions share untouched (see `touched`), which are skipped.
+    ///
+    /// A sub tree rooted at the same page in both versions is compared level by level, while
+    /// the sub trees found in a single version so far are descended into highest first, so that
+    /// a page moved under another parent (e.g. under a new root) is found in both versions at
+    /// the same height, and compared rather than read in full in either of them.
+    fn walk<S, F>(&mut self, pager: &mut Pager<S>, mut visit: F) -> Result<(), Error>
+    where
+        S: Storage,
+        F: FnMut(usize, PageId, &Node),
+    {
+        let touched = self.touched(pager)?;
+        // The sub trees left to visit in either version, alongside their heights.
+        let mut pending: [Vec<(PageId, usize)>; 2] = [Vec::new(), Vec::new()];
+        for version in [EARLIER, LATER] {
+            if let Some(root) = self.roots[version] {
+                let height = self.height(pager, version, root)?;
+                pending[version].push((root, height));
+            }
+        }
+        loop {
+            let later: HashSet<PageId> = pending[LATER].iter().map(|(id, _)| *id).collect();
+            let shared: HashSet<PageId> = pending[EARLIER]
+                .iter()
+                .map(|(id, _)| *id)
+                .filter(|id| later.contains(id))
+                .collect();
+            // Sub trees found in both versions are descended into together, unless untouched.
+            let mut expanded: [Vec<(PageId, usize)>; 2] = [Vec::new(), Vec::new()];
+            if !shared.is_empty() {
+                for version in [EARLIER, LATER] {
+                    let (matched, left) = std::mem::take(&mut pending[version])
+                        .into_iter()
+                        .partition(|(id, _)| shared.contains(id));
+                    pending[version] = left;
+                    expanded[version] = matched;
+                }
+                let untouched = |id: &PageId| touched.as_ref().is_some_and(|t| !t.contains(id));
+                for version in [EARLIER, LATER] {
+                    expanded[version].retain(|(id, _)| !untouched(id));
+                }
+            } else {
+                // Otherwise the highest sub trees found in a single version are descended into.
+                let height = pending.iter().flatten().map(|(_, height)| *height).max();
+                let height = match height {
+                    Some(height) => height,
+                    None => return Ok(()),
+                };
+                for version in [EARLIER, LATER] {
+                    let (highest, left) = std::mem::take(&mut pending[version])
+                        .into_iter()
+                        .partition(|(_, h)| *h == height);
+                    pending[version] = left;
+                    expanded[version] = highest;
+                }
+            }
+            for version in [EARLIER, LATER] {
+                for (id, height) in std::mem::take(&mut expanded[version]) {
+                    let node = self.node(pager, version, &id)?;
+                    visit(version, id, &node);
+                    if let NodeType::Internal(children, _, _) = &node.node_type {
+                        let height = height.checked_sub(1).ok_or(Error::UnexpectedError)?;
+                        pending[version].extend(children.iter().map(|child| (*child, height)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// prefix_range returns the range of the keys starting with a given prefix: from the prefix
+/// followed by zeros up to the prefix followed by 0xFF bytes.
+/// Fails with `Error::KeyOverflowError` if the prefix is longer than a key.
+fn prefix_range(prefix: &[u8]) -> Result<RangeInclusive<Key>, Error> {
+    if prefix.len() > KEY_SIZE {
+        return Err(Error::KeyOverflowError);
+    }
+    let (mut start, mut end) = ([0x00; KEY_SIZE], [0xFF; KEY_SIZE]);
+    start[..prefix.len()].copy_from_slice(prefix);
+    end[..prefix.len()].copy_from_slice(prefix);
+    Ok(Key(start)..=Key(end))
+}
+
+/// now returns the current time in milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// bound_key returns the key of a bound, or a key made of a given byte if unbounded.
+fn bound_key(bound: &Bound<Key>, byte: u8) -> Key {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) => key.clone(),
+        Bound::Unbounded => Key([byte; KEY_SIZE]),
+    }
+}
+
+/// is_empty_range returns whether a range holds no keys at all, as its start is past its end.
+fn is_empty_range(range: &(Bound<Key>, Bound<Key>)) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start >= end,
+        _ => false,
+    }
+}
+
+/// insert_separators adds the nodes split off the child at a given index of an internal node,
+/// alongside the keys separating them, right after that child, which is referred to by
+/// the id it was written under.
+/// If the internal node keeps subtree counts, the count of the child is set to what is left
+/// of a given count, which the child held before it was split, once the new nodes are counted.
+fn insert_separators(
+    parent: &mut Node,
+    idx: usize,
+    id: PageId,
+    count: Option<usize>,
+    separators: Vec<(Key, PageId, Option<usize>)>,
+) -> Result<(), Error> {
+    let (children, keys, counts) = match parent.node_type {
+        NodeType::Internal(ref mut children, ref mut keys, ref mut counts) => {
+            (children, keys, counts)
+        }
+        _ => return Err(Error::UnexpectedError),
+    };
+    *children.get_mut(idx).ok_or(Error::UnexpectedError)? = id;
+    let counted = !counts.is_empty();
+    let mut count = count;
+    for (i, (separator, id, sibling_count)) in separators.into_iter().enumerate() {
+        keys.insert(idx + i, separator);
+        children.insert(idx + i + 1, id);
+        if counted {
+            let sibling_count = sibling_count.ok_or(Error::UnexpectedError)?;
+            counts.insert(idx + i + 1, sibling_count);
+            count = count.and_then(|count| count.checked_sub(sibling_count));
+        }
+    }
+    if counted {
+        counts[idx] = count.ok_or(Error::UnexpectedError)?;
+    }
+    Ok(())
+}
+
+impl BTree<MemoryStorage> {
+    /// dump persists an in-memory tree into a tree file (and WAL) at a given path,
+    /// which can then be reopened by building a tree at that path.
+    /// Fails with `Error::Locked` rather than overwrite a tree which is currently open.
+    pub fn dump(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        for (storage, path) in [
+            (self.pager.storage(), path.to_path_buf()),
+            (self.wal.storage(), default_wal_path(path)?),
+        ] {
+            let mut file = FileStorage::new(&path)?;
+            file.try_lock()?;
+            file.truncate(0)?;
+            file.write_at(storage.as_bytes(), 0)?;
+            file.sync()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
 mod tests {
-    use uuid::Uuid;
     use crate::error::Error;
+    use std::fs;
+    use std::path::Path;
+    use uuid::Uuid;
+
+    /// clean removes the tree file and WAL left behind at a given path by previous runs.
+    fn clean(path: &Path) -> Result<(), Error> {
+        let parent_directory = path.parent().ok_or(Error::UnexpectedError)?;
+        fs::create_dir_all(parent_directory)?;
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(super::default_wal_path(path)?);
+        let _ = fs::remove_file(super::lock_path(path)?);
+        Ok(())
+    }
 
     #[test]
     fn search_works() -> Result<(), Error> {
         use crate::btree::BTreeBuilder;
         use crate::node_type::KeyValuePair;
-        use std::path::Path;
 
         let id_a = Uuid::now_v7();
         let id_b = Uuid::now_v7();
         let id_c = Uuid::now_v7();
-        let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
-            .b_parameter(2)
-            .build()?;
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
         btree.insert(KeyValuePair::new(id_a.into_bytes(), "shalom".to_string()))?;
         btree.insert(KeyValuePair::new(id_b.into_bytes(), "hello".to_string()))?;
         btree.insert(KeyValuePair::new(id_c.into_bytes(), "marhaba".to_string()))?;
@@ -450,12 +2113,8 @@ mod tests {
     fn insert_works() -> Result<(), Error> {
         use crate::btree::BTreeBuilder;
         use crate::node_type::KeyValuePair;
-        use std::path::Path;
 
-        let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
-            .b_parameter(2)
-            .build()?;
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
         let a = Uuid::now_v7();
         let b = Uuid::now_v7();
         let c = Uuid::now_v7();
@@ -510,6 +2169,20 @@ mod tests {
         kv = btree.search(&i.into_bytes())?;
         assert_eq!(kv.key, i.into_bytes());
         assert_eq!(kv.value, "Ciao");
+
+        // Writing a key again replaces its pair, even one which has expired.
+        btree.insert(KeyValuePair::with_expiry(
+            a.into_bytes(),
+            "gone".to_string(),
+            1,
+        ))?;
+        assert!(matches!(
+            btree.search(&a.into_bytes()),
+            Err(Error::KeyNotFound)
+        ));
+        btree.insert(KeyValuePair::new(a.into_bytes(), "shalom!".to_string()))?;
+        assert_eq!(btree.search(&a.into_bytes())?.value, "shalom!");
+        assert_eq!(btree.len()?, 9);
         Ok(())
     }
 
@@ -518,7 +2191,6 @@ mod tests {
         use crate::btree::BTreeBuilder;
         use crate::error::Error;
         use crate::node_type::{Key, KeyValuePair};
-        use std::path::Path;
 
         let a = Uuid::now_v7();
         let b = Uuid::now_v7();
@@ -526,10 +2198,7 @@ mod tests {
         let d = Uuid::now_v7();
         let e = Uuid::now_v7();
         let f = Uuid::now_v7();
-        let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
-            .b_parameter(2)
-            .build()?;
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
         btree.insert(KeyValuePair::new(d.into_bytes(), "olah".to_string()))?;
         btree.insert(KeyValuePair::new(e.into_bytes(), "salam".to_string()))?;
         btree.insert(KeyValuePair::new(f.into_bytes(), "hallo".to_string()))?;
@@ -568,12 +2237,8 @@ mod tests {
     fn delete_with_empty_sub_tree() -> Result<(), Error> {
         use crate::btree::BTreeBuilder;
         use crate::node_type::{Key, KeyValuePair};
-        use std::path::Path;
 
-        let mut btree = BTreeBuilder::new()
-            .path(Path::new("/tmp/db"))
-            .b_parameter(2)
-            .build()?;
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
         let a = Uuid::now_v7();
         let b = Uuid::now_v7();
         let c = Uuid::now_v7();
@@ -622,4 +2287,1583 @@ mod tests {
         assert!(matches!(res, Err(Error::KeyNotFound)));
         Ok(())
     }
+
+    #[test]
+    fn delete_all_keys_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
+        let mut keys: Vec<[u8; 16]> = (0..200).map(|_| Uuid::new_v4().into_bytes()).collect();
+        for key in keys.iter() {
+            btree.insert(KeyValuePair::new(*key, "value".to_string()))?;
+        }
+
+        // Delete the keys in an order unrelated to the one they were inserted in,
+        // merging nodes all over the tree.
+        keys.sort();
+        let mut remaining = keys.clone();
+        for key in keys.iter().step_by(2).chain(keys.iter().skip(1).step_by(2)) {
+            btree.delete(Key(*key))?;
+            remaining.retain(|remaining_key| remaining_key != key);
+            let in_tree: Vec<[u8; 16]> = btree.range(..)?.iter().map(|kv| kv.key).collect();
+            assert_eq!(in_tree, remaining);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn range_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::collections::btree_map::Range;
+        use std::collections::BTreeMap;
+
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
+        let mut model = BTreeMap::new();
+        let keys: Vec<[u8; 16]> = (0..60).map(|_| Uuid::now_v7().into_bytes()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            btree.insert(KeyValuePair::new(*key, format!("v{}", i)))?;
+            model.insert(*key, format!("v{}", i));
+        }
+        for key in keys.iter().step_by(3) {
+            btree.delete(Key(*key))?;
+            model.remove(key);
+        }
+        let pairs = |range: Range<[u8; 16], String>| -> Vec<KeyValuePair> {
+            range
+                .map(|(key, value)| KeyValuePair::new(*key, value.clone()))
+                .collect()
+        };
+
+        assert_eq!(btree.range(..)?, pairs(model.range::<[u8; 16], _>(..)));
+        assert_eq!(
+            btree.range(Key(keys[10])..Key(keys[40]))?,
+            pairs(model.range(keys[10]..keys[40]))
+        );
+        assert_eq!(
+            btree.range(Key(keys[10])..=Key(keys[40]))?,
+            pairs(model.range(keys[10]..=keys[40]))
+        );
+        // Bounds need not be in the tree.
+        assert_eq!(
+            btree.range(Key(keys[12])..=Key(keys[30]))?,
+            pairs(model.range(keys[12]..=keys[30]))
+        );
+        assert_eq!(btree.range(Key(keys[40])..Key(keys[10]))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+
+        // Trees which do not keep subtree counts count the leaves in range instead.
+        for subtree_counts in [true, false] {
+            let mut btree = BTreeBuilder::in_memory()
+                .b_parameter(2)
+                .subtree_counts(subtree_counts)
+                .build()?;
+            // Keys made of a tenant id followed by an object id.
+            for tenant in [0x00u8, 0x41, 0x42, 0xFF] {
+                for object in 0..20u8 {
+                    let mut key = [tenant; 16];
+                    key[4] = object;
+                    btree.insert(KeyValuePair::new(key, format!("{}", object)))?;
+                }
+            }
+
+            let mut pairs = Vec::new();
+            btree.scan_prefix(&[0x41; 4], |pair| pairs.push(pair))?;
+            assert_eq!(pairs.len(), 20);
+            for (object, pair) in pairs.iter().enumerate() {
+                assert_eq!(pair.key[..4], [0x41; 4]);
+                assert_eq!(pair.value, format!("{}", object));
+            }
+            for tenant in [0x00u8, 0x41, 0x42, 0xFF] {
+                assert_eq!(btree.count_prefix(&[tenant; 4])?, 20);
+            }
+            assert_eq!(btree.count_prefix(&[0x41, 0x41, 0x41, 0x41, 0x03])?, 1);
+            assert_eq!(btree.count_prefix(&[0x43])?, 0);
+            assert_eq!(btree.count_prefix(&[])?, 80);
+            assert!(matches!(
+                btree.scan_prefix(&[0x00; 17], |_| {}),
+                Err(Error::KeyOverflowError)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn order_statistics_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::collections::BTreeMap;
+
+        // Trees which do not keep subtree counts answer the same, by counting leaf by leaf.
+        for subtree_counts in [true, false] {
+            let mut btree = BTreeBuilder::in_memory()
+                .b_parameter(2)
+                .subtree_counts(subtree_counts)
+                .build()?;
+            let mut model = BTreeMap::new();
+            for i in 0..300 {
+                let key = Uuid::new_v4().into_bytes();
+                btree.insert(KeyValuePair::new(key, i.to_string()))?;
+                model.insert(key, i.to_string());
+            }
+            let keys: Vec<[u8; 16]> = model.keys().cloned().collect();
+            for key in keys.iter().step_by(3) {
+                btree.delete(Key(*key))?;
+                model.remove(key);
+            }
+            let root_id = btree.root()?;
+            assert_eq!(btree.get_node(&root_id)?.count().is_some(), subtree_counts);
+
+            assert_eq!(btree.len()?, model.len());
+            assert!(!btree.is_empty()?);
+            for (i, (key, value)) in model.iter().enumerate() {
+                assert_eq!(btree.rank(&Key(*key))?, i);
+                let pair = btree.select(i)?;
+                assert_eq!((pair.key, pair.value), (*key, value.clone()));
+            }
+            assert!(matches!(btree.select(model.len()), Err(Error::KeyNotFound)));
+            // Deleted keys rank where they would be inserted.
+            for key in keys.iter().step_by(3) {
+                assert_eq!(btree.rank(&Key(*key))?, model.range(..*key).count());
+            }
+
+            let (a, b) = (keys[10], keys[200]);
+            assert_eq!(btree.count_range(..)?, model.len());
+            assert_eq!(
+                btree.count_range(Key(a)..Key(b))?,
+                model.range(a..b).count()
+            );
+            assert_eq!(
+                btree.count_range(Key(a)..=Key(b))?,
+                model.range(a..=b).count()
+            );
+            assert_eq!(btree.count_range(..Key(b))?, model.range(..b).count());
+            assert_eq!(btree.count_range(Key(a)..)?, model.range(a..).count());
+            assert_eq!(btree.count_range(Key(b)..Key(a))?, 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn subtree_counts_are_added_to_existing_trees() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+
+        let path = Path::new("/tmp/subtree_counts_are_added_to_existing_trees/db");
+        clean(path)?;
+
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(
+                Uuid::now_v7().into_bytes(),
+                i.to_string(),
+            ))?;
+        }
+        let root_id = btree.root()?;
+        assert_eq!(btree.get_node(&root_id)?.count(), None);
+        drop(btree);
+
+        let mut btree = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(2)
+            .subtree_counts(true)
+            .build()?;
+        let root_id = btree.root()?;
+        assert_eq!(btree.get_node(&root_id)?.count(), Some(50));
+        drop(btree);
+
+        // Counts are kept up to date once a tree keeps them, whether asked to or not.
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        for i in 50..100 {
+            btree.insert(KeyValuePair::new(
+                Uuid::now_v7().into_bytes(),
+                i.to_string(),
+            ))?;
+        }
+        let root_id = btree.root()?;
+        assert_eq!(btree.get_node(&root_id)?.count(), Some(100));
+        assert_eq!(btree.select(99)?.value, "99");
+        Ok(())
+    }
+
+    #[test]
+    fn floor_and_ceiling_lookups_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
+        assert!(matches!(btree.first(), Err(Error::KeyNotFound)));
+        assert!(matches!(btree.last(), Err(Error::KeyNotFound)));
+
+        // Even keys only, spread over many leaves.
+        let key = |i: u8| {
+            let mut key = [0x00; 16];
+            key[15] = i;
+            key
+        };
+        for i in (2..=100).step_by(2) {
+            btree.insert(KeyValuePair::new(key(i), i.to_string()))?;
+        }
+
+        assert_eq!(btree.first()?.key, key(2));
+        assert_eq!(btree.last()?.key, key(100));
+        for i in 1..=101 {
+            let (floor, lower) = match i % 2 {
+                0 => (i, i - 2),
+                _ => (i - 1, i - 1),
+            };
+            let (ceiling, higher) = match i % 2 {
+                0 => (i, i + 2),
+                _ => (i + 1, i + 1),
+            };
+            for (res, expected) in [
+                (btree.floor(&Key(key(i))), floor),
+                (btree.lower(&Key(key(i))), lower),
+                (btree.ceiling(&Key(key(i))), ceiling),
+                (btree.higher(&Key(key(i))), higher),
+            ] {
+                match (2..=100).contains(&expected) {
+                    true => assert_eq!(res?.value, expected.to_string()),
+                    false => assert!(matches!(res, Err(Error::KeyNotFound))),
+                }
+            }
+        }
+
+        // Lookups cross over leaves which no longer hold any key in range.
+        for i in (20..=80).step_by(2) {
+            btree.delete(Key(key(i)))?;
+        }
+        assert_eq!(btree.floor(&Key(key(50)))?.key, key(18));
+        assert_eq!(btree.ceiling(&Key(key(50)))?.key, key(82));
+        Ok(())
+    }
+
+    #[test]
+    fn get_many_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
+        let mut keys = vec![];
+        for i in 0..100 {
+            let key = Uuid::new_v4().into_bytes();
+            btree.insert(KeyValuePair::new(key, i.to_string()))?;
+            keys.push(key);
+        }
+        assert!(btree.get_many(&[])?.is_empty());
+
+        // Probe every other key, missing keys and a duplicate, in no particular order.
+        let mut probes: Vec<Key> = keys.iter().step_by(2).map(|key| Key(*key)).collect();
+        probes.push(Key(Uuid::new_v4().into_bytes()));
+        probes.push(Key(keys[42]));
+        probes.insert(10, Key([0x00; 16]));
+        probes.insert(0, Key([0xFF; 16]));
+
+        let res = btree.get_many(&probes)?;
+        assert_eq!(res.len(), probes.len());
+        for (Key(key), pair) in probes.iter().zip(res) {
+            match btree.search(key) {
+                Ok(expected) => assert_eq!(pair, Some(expected)),
+                Err(Error::KeyNotFound) => assert_eq!(pair, None),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn apply_batch_works() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, Op};
+        use crate::node_type::{Key, KeyValuePair, NodeType};
+        use std::collections::BTreeMap;
+
+        for subtree_counts in [true, false] {
+            let mut btree = BTreeBuilder::in_memory()
+                .b_parameter(2)
+                .subtree_counts(subtree_counts)
+                .build()?;
+            let mut model = BTreeMap::new();
+            let mut keys: Vec<[u8; 16]> = vec![];
+            for round in 0..5 {
+                // Insert new keys alongside deleting every other key inserted so far.
+                let mut ops = vec![];
+                for key in keys.iter().step_by(2) {
+                    ops.push(Op::Delete(Key(*key)));
+                    model.remove(key);
+                }
+                for i in 0..200 {
+                    let key = Uuid::new_v4().into_bytes();
+                    ops.push(Op::Insert(KeyValuePair::new(
+                        key,
+                        format!("{}-{}", round, i),
+                    )));
+                    model.insert(key, format!("{}-{}", round, i));
+                }
+                keys = model.keys().cloned().collect();
+                btree.apply_batch(ops)?;
+
+                let pairs = btree.range(..)?;
+                assert_eq!(pairs.len(), model.len());
+                for (pair, (key, value)) in pairs.iter().zip(model.iter()) {
+                    assert_eq!((&pair.key, &pair.value), (key, value));
+                }
+                assert_eq!(btree.len()?, model.len());
+            }
+
+            // Deleting all but a few keys shrinks the tree back to a root above its leaves.
+            let ops = keys
+                .iter()
+                .skip(3)
+                .map(|key| Op::Delete(Key(*key)))
+                .collect();
+            btree.apply_batch(ops)?;
+            let pairs = btree.range(..)?;
+            assert_eq!(pairs.len(), 3);
+            for (pair, key) in pairs.iter().zip(&keys) {
+                assert_eq!(pair.key, *key);
+            }
+            let root_id = btree.root()?;
+            if let NodeType::Internal(children, _, _) = btree.get_node(&root_id)?.node_type {
+                for child_id in children {
+                    let child = btree.get_node(&child_id)?;
+                    assert!(matches!(child.node_type, NodeType::Leaf(_)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn batches_copy_nodes_once() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, Op};
+        use crate::node_type::{KeyValuePair, NodeType};
+        use crate::page_layout::PAGE_SIZE;
+
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
+        let pages = btree.pager.storage().as_bytes().len() / PAGE_SIZE;
+        let ops = (0..500)
+            .map(|i| {
+                Op::Insert(KeyValuePair::new(
+                    Uuid::now_v7().into_bytes(),
+                    i.to_string(),
+                ))
+            })
+            .collect();
+        btree.apply_batch(ops)?;
+
+        // Every node of the tree was written once, the nodes split off the root included.
+        let mut reachable = 0;
+        let mut ids = vec![btree.root()?];
+        while let Some(id) = ids.pop() {
+            reachable += 1;
+            if let NodeType::Internal(children, _, _) = btree.get_node(&id)?.node_type {
+                ids.extend(children);
+            }
+        }
+        let written = btree.pager.storage().as_bytes().len() / PAGE_SIZE - pages;
+        assert_eq!(written, reachable);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_batches_are_not_applied() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, Op};
+        use crate::node_type::{Key, KeyValuePair};
+
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
+        let (a, b) = (Uuid::now_v7().into_bytes(), Uuid::now_v7().into_bytes());
+        btree.insert(KeyValuePair::new(a, "a".to_string()))?;
+
+        // Writes to the same key are applied in order.
+        btree.apply_batch(vec![
+            Op::Insert(KeyValuePair::new(b, "b".to_string())),
+            Op::Delete(Key(b)),
+            Op::Delete(Key(a)),
+            Op::Insert(KeyValuePair::new(a, "c".to_string())),
+        ])?;
+        assert_eq!(btree.search(&a)?.value, "c");
+        assert!(matches!(btree.search(&b), Err(Error::KeyNotFound)));
+
+        let res = btree.apply_batch(vec![
+            Op::Delete(Key(a)),
+            Op::Insert(KeyValuePair::new(b, "b".to_string())),
+            Op::Delete(Key([0x00; 16])),
+        ]);
+        assert!(matches!(res, Err(Error::KeyNotFound)));
+        assert_eq!(btree.search(&a)?.value, "c");
+        assert!(matches!(btree.search(&b), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn delete_range_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair, NodeType};
+        use std::collections::BTreeMap;
+        use std::ops::Bound;
+
+        for subtree_counts in [true, false] {
+            let mut btree = BTreeBuilder::in_memory()
+                .b_parameter(2)
+                .subtree_counts(subtree_counts)
+                .build()?;
+            let mut model = BTreeMap::new();
+            for i in 0..400 {
+                let key = Uuid::new_v4().into_bytes();
+                btree.insert(KeyValuePair::new(key, i.to_string()))?;
+                model.insert(key, i.to_string());
+            }
+            let keys: Vec<[u8; 16]> = model.keys().cloned().collect();
+            // A key which is not in the tree, sorting before keys[330].
+            let mut missing = keys[310];
+            missing[15] = missing[15].wrapping_add(1);
+            let ranges = [
+                (Bound::Included(keys[50]), Bound::Excluded(keys[300])),
+                (Bound::Unbounded, Bound::Included(keys[20])),
+                (Bound::Excluded(keys[350]), Bound::Unbounded),
+                (Bound::Included(keys[320]), Bound::Included(keys[320])),
+                (Bound::Included(missing), Bound::Included(keys[330])),
+                (Bound::Unbounded, Bound::Unbounded),
+            ];
+            // Empty and reversed ranges delete nothing, and leave the leaves linked as they were.
+            btree.delete_range(Key(keys[80])..Key(keys[10]))?;
+            btree.delete_range((
+                Bound::Excluded(Key(keys[40])),
+                Bound::Included(Key(keys[40])),
+            ))?;
+            btree.delete_range(Key(keys[60])..Key(keys[60]))?;
+            let pairs = btree.range(..)?;
+            assert!(pairs.iter().map(|pair| pair.key).eq(model.keys().cloned()));
+            assert_eq!(btree.len()?, model.len());
+
+            for (start, end) in ranges {
+                let key_range = (start.map(Key), end.map(Key));
+                btree.delete_range(key_range)?;
+                let deleted: Vec<[u8; 16]> = model.range((start, end)).map(|(k, _)| *k).collect();
+                for key in deleted {
+                    model.remove(&key);
+                }
+
+                let pairs = btree.range(..)?;
+                assert_eq!(pairs.len(), model.len());
+                for (pair, (key, value)) in pairs.iter().zip(model.iter()) {
+                    assert_eq!((&pair.key, &pair.value), (key, value));
+                }
+                assert_eq!(btree.len()?, model.len());
+
+                // Walking the leaves backwards from the last one visits every key as well.
+                let root_id = btree.root()?;
+                let mut id = root_id;
+                while let NodeType::Internal(children, _, _) = btree.get_node(&id)?.node_type {
+                    id = children[children.len() - 1];
+                }
+                let mut backwards = vec![];
+                let mut leaf = Some(id);
+                while let Some(id) = leaf {
+                    let node = btree.get_node(&id)?;
+                    if let NodeType::Leaf(pairs) = node.node_type {
+                        backwards.extend(pairs.into_iter().rev().map(|pair| pair.key));
+                    }
+                    leaf = node.prev;
+                }
+                assert!(backwards.iter().rev().eq(model.keys()));
+            }
+
+            // The tree is left in a state which can be written to.
+            for i in 0..50 {
+                let key = Uuid::new_v4().into_bytes();
+                btree.insert(KeyValuePair::new(key, i.to_string()))?;
+                model.insert(key, i.to_string());
+            }
+            let pairs = btree.range(..)?;
+            assert!(pairs.iter().map(|pair| pair.key).eq(model.keys().cloned()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn delete_range_drops_whole_sub_trees() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
+        let keys: Vec<[u8; 16]> = (0..2000).map(|_| Uuid::now_v7().into_bytes()).collect();
+        for key in keys.iter() {
+            btree.insert(KeyValuePair::new(*key, "value".to_string()))?;
+        }
+
+        // Only the nodes around either end of the range are written, out of hundreds of nodes.
+        let pages = btree.pager.storage().as_bytes().len() / PAGE_SIZE;
+        btree.delete_range(Key(keys[100])..Key(keys[1900]))?;
+        let written = btree.pager.storage().as_bytes().len() / PAGE_SIZE - pages;
+        assert!(written < 100, "{} pages written", written);
+        assert_eq!(btree.count_range(..)?, 200);
+        Ok(())
+    }
+
+    #[test]
+    fn expired_pairs_are_hidden_and_purged() -> Result<(), Error> {
+        use crate::btree::{now, BTreeBuilder};
+        use crate::node_type::{Key, KeyValuePair};
+        use std::time::Duration;
+        use uuid::{NoContext, Timestamp};
+
+        let path = Path::new("/tmp/expired_pairs_are_hidden_and_purged/db");
+        clean(path)?;
+
+        let mut btree = BTreeBuilder::in_memory()
+            .b_parameter(2)
+            .subtree_counts(true)
+            .build()?;
+        let keys: Vec<[u8; 16]> = (0..60).map(|_| Uuid::now_v7().into_bytes()).collect();
+        let later = now() + 3_600_000;
+        for (i, key) in keys.iter().enumerate() {
+            let pair = match i % 3 {
+                0 => KeyValuePair::with_expiry(*key, i.to_string(), 1),
+                1 => KeyValuePair::with_expiry(*key, i.to_string(), later),
+                _ => KeyValuePair::new(*key, i.to_string()),
+            };
+            btree.insert(pair)?;
+        }
+        // Expiry times survive being written to disk.
+        btree.dump(path)?;
+        let mut btree = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(2)
+            .subtree_counts(true)
+            .build()?;
+
+        let expected: Vec<[u8; 16]> = keys
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 3 != 0)
+            .map(|(_, key)| *key)
+            .collect();
+        assert!(matches!(btree.search(&keys[0]), Err(Error::KeyNotFound)));
+        assert_eq!(btree.search(&keys[1])?.expires_at, Some(later));
+        assert_eq!(btree.search(&keys[2])?.expires_at, None);
+        assert_eq!(btree.get_many(&[Key(keys[3]), Key(keys[4])])?[0], None);
+        assert_eq!(btree.first()?.key, keys[1]);
+        assert_eq!(btree.floor(&Key(keys[3]))?.key, keys[2]);
+        assert_eq!(btree.ceiling(&Key(keys[3]))?.key, keys[4]);
+        let pairs = btree.range(..)?;
+        assert!(pairs
+            .iter()
+            .map(|pair| pair.key)
+            .eq(expected.iter().cloned()));
+
+        // Expired pairs are counted, and keep their positions, until they are purged.
+        assert_eq!(btree.len()?, 60);
+        assert_eq!(btree.count_range(Key(keys[0])..Key(keys[3]))?, 3);
+        assert_eq!(btree.rank(&Key(keys[1]))?, 1);
+        let pair = btree.select(0)?;
+        assert_eq!(pair.key, keys[0]);
+        assert!(pair.is_expired(now()));
+        assert_eq!(btree.count_prefix(&keys[0])?, 1);
+        let mut scanned = 0;
+        btree.scan_prefix(&keys[0], |_| scanned += 1)?;
+        assert_eq!(scanned, 0);
+        assert_eq!(btree.purge_expired()?, 20);
+        assert_eq!(btree.select(0)?.key, keys[1]);
+        assert_eq!(btree.count_prefix(&keys[0])?, 0);
+        assert_eq!(btree.len()?, 40);
+        assert_eq!(btree.purge_expired()?, 0);
+        let pairs = btree.range(..)?;
+        assert!(pairs
+            .iter()
+            .map(|pair| pair.key)
+            .eq(expected.iter().cloned()));
+
+        // UUIDv7 keys generated over an hour ago are purged by their timestamp alone.
+        let secs = (now() - 2 * 3_600_000) / 1000;
+        for _ in 0..10 {
+            let key = Uuid::new_v7(Timestamp::from_unix(NoContext, secs, 0)).into_bytes();
+            btree.insert(KeyValuePair::new(key, "old".to_string()))?;
+        }
+        assert_eq!(btree.len()?, 50);
+        btree.purge_keys_older_than(Duration::from_secs(3600))?;
+        let pairs = btree.range(..)?;
+        assert!(pairs
+            .iter()
+            .map(|pair| pair.key)
+            .eq(expected.iter().cloned()));
+        assert_eq!(btree.len()?, 40);
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_swap_works() -> Result<(), Error> {
+        use crate::btree::{now, BTreeBuilder};
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+
+        let mut btree = BTreeBuilder::in_memory()
+            .b_parameter(2)
+            .subtree_counts(true)
+            .build()?;
+        let keys: Vec<[u8; 16]> = (0..30).map(|_| Uuid::now_v7().into_bytes()).collect();
+        for key in keys.iter().step_by(2) {
+            btree.insert(KeyValuePair::new(*key, "v1".to_string()))?;
+        }
+
+        // Failed expectations leave the tree untouched, nothing is written.
+        let pages = btree.pager.storage().as_bytes().len() / PAGE_SIZE;
+        for (key, expected) in [
+            (keys[0], None),
+            (keys[0], Some("v2".to_string())),
+            (keys[1], Some("v1".to_string())),
+        ] {
+            let res = btree.compare_and_swap(Key(key), expected, Some("v3".to_string()));
+            assert!(matches!(res, Err(Error::Conflict)));
+        }
+        let res = btree.compare_and_delete(Key(keys[2]), "v2".to_string());
+        assert!(matches!(res, Err(Error::Conflict)));
+        assert_eq!(btree.pager.storage().as_bytes().len() / PAGE_SIZE, pages);
+        assert_eq!(btree.search(&keys[0])?.value, "v1");
+        assert_eq!(btree.search(&keys[2])?.value, "v1");
+        assert!(matches!(btree.search(&keys[1]), Err(Error::KeyNotFound)));
+
+        // Met expectations update, insert and delete keys.
+        btree.compare_and_swap(Key(keys[0]), Some("v1".to_string()), Some("v2".to_string()))?;
+        btree.compare_and_swap(Key(keys[1]), None, Some("v2".to_string()))?;
+        btree.compare_and_delete(Key(keys[2]), "v1".to_string())?;
+        // Deleting a key which is absent commits nothing.
+        let sequence = btree.sequence()?;
+        btree.compare_and_swap(Key(keys[3]), None, None)?;
+        assert_eq!(btree.sequence()?, sequence);
+        assert_eq!(btree.search(&keys[0])?.value, "v2");
+        assert_eq!(btree.search(&keys[1])?.value, "v2");
+        assert!(matches!(btree.search(&keys[2]), Err(Error::KeyNotFound)));
+        assert!(matches!(btree.search(&keys[3]), Err(Error::KeyNotFound)));
+        assert_eq!(btree.len()?, 15);
+
+        // Expired pairs are expected to be absent.
+        btree.insert(KeyValuePair::with_expiry(keys[5], "v1".to_string(), 1))?;
+        let res = btree.compare_and_swap(Key(keys[5]), Some("v1".to_string()), None);
+        assert!(matches!(res, Err(Error::Conflict)));
+        btree.compare_and_swap(Key(keys[5]), None, Some("v2".to_string()))?;
+        assert_eq!(btree.search(&keys[5])?.value, "v2");
+        assert_eq!(btree.search(&keys[5])?.expires_at, None);
+        assert_eq!(btree.len()?, 16);
+
+        // Swapped values keep the expiry time of the values they replace.
+        let expires_at = now() + 60_000;
+        btree.insert(KeyValuePair::with_expiry(
+            keys[7],
+            "v1".to_string(),
+            expires_at,
+        ))?;
+        btree.compare_and_swap(Key(keys[7]), Some("v1".to_string()), Some("v2".to_string()))?;
+        let pair = btree.search(&keys[7])?;
+        assert_eq!(pair.value, "v2");
+        assert_eq!(pair.expires_at, Some(expires_at));
+        Ok(())
+    }
+
+    #[test]
+    fn change_feed_works() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, Op};
+        use crate::change::{Change, ChangeSet};
+        use crate::node_type::{Key, KeyValuePair};
+        use std::ops::Bound;
+
+        let path = Path::new("/tmp/change_feed_works/db");
+        clean(path)?;
+
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        let mut reader = BTreeBuilder::new().path(path).read_only(true).build()?;
+        let subscription = btree.subscribe();
+        let reader_subscription = reader.subscribe();
+        let keys: Vec<[u8; 16]> = (0..4).map(|_| Uuid::now_v7().into_bytes()).collect();
+        let a = KeyValuePair::new(keys[0], "a".to_string());
+        let b = KeyValuePair::new(keys[1], "b".to_string());
+        btree.insert(a.clone())?;
+        btree.apply_batch(vec![Op::Insert(b.clone()), Op::Delete(Key(keys[0]))])?;
+        // Failed writes publish nothing.
+        assert!(btree.delete(Key(keys[3])).is_err());
+        btree.compare_and_swap(Key(keys[1]), Some("b".to_string()), Some("c".to_string()))?;
+        btree.delete_range(Key(keys[1])..)?;
+
+        let expected = vec![
+            ChangeSet {
+                sequence: 2,
+                changes: vec![Change::Put(a)],
+            },
+            ChangeSet {
+                sequence: 3,
+                // The writes of a batch are applied in key order.
+                changes: vec![Change::Delete(Key(keys[0])), Change::Put(b)],
+            },
+            ChangeSet {
+                sequence: 4,
+                changes: vec![Change::Put(KeyValuePair::new(keys[1], "c".to_string()))],
+            },
+            ChangeSet {
+                sequence: 5,
+                changes: vec![Change::DeleteRange(
+                    Bound::Included(Key(keys[1])),
+                    Bound::Unbounded,
+                )],
+            },
+        ];
+        assert_eq!(subscription.try_iter().collect::<Vec<_>>(), expected);
+
+        // Readers publish the commits of the writer as they pick them up.
+        assert!(reader_subscription.try_recv().is_err());
+        assert!(reader.first().is_err());
+        assert_eq!(reader_subscription.try_iter().collect::<Vec<_>>(), expected);
+
+        // Consumers catch up from the last sequence number they saw after a restart.
+        drop(btree);
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        assert_eq!(btree.changes_since(0)?, expected);
+        assert_eq!(btree.changes_since(3)?, expected[2..].to_vec());
+        assert!(btree.changes_since(5)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn diff_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::change::Diff;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::collections::{BTreeMap, BTreeSet};
+
+        for subtree_counts in [true, false] {
+            let mut btree = BTreeBuilder::in_memory()
+                .b_parameter(2)
+                .subtree_counts(subtree_counts)
+                .build()?;
+            let mut model = BTreeMap::new();
+            for i in 0..300 {
+                let key = Uuid::new_v4().into_bytes();
+                btree.insert(KeyValuePair::new(key, i.to_string()))?;
+                model.insert(key, KeyValuePair::new(key, i.to_string()));
+            }
+            let before = btree.sequence()?;
+            let old = model.clone();
+
+            let keys: Vec<[u8; 16]> = model.keys().cloned().collect();
+            for key in keys.iter().step_by(15) {
+                btree.delete(Key(*key))?;
+                model.remove(key);
+            }
+            for key in keys.iter().skip(1).step_by(15) {
+                let value = model[key].value.clone();
+                btree.compare_and_swap(Key(*key), Some(value), Some("new".to_string()))?;
+                model.insert(*key, KeyValuePair::new(*key, "new".to_string()));
+            }
+            btree.delete_range(Key(keys[100])..Key(keys[200]))?;
+            model.retain(|key, _| !(keys[100]..keys[200]).contains(key));
+            for i in 0..20 {
+                let key = Uuid::new_v4().into_bytes();
+                btree.insert(KeyValuePair::new(key, i.to_string()))?;
+                model.insert(key, KeyValuePair::new(key, i.to_string()));
+            }
+            let after = btree.sequence()?;
+
+            let mut expected = Vec::new();
+            let keys: BTreeSet<&[u8; 16]> = old.keys().chain(model.keys()).collect();
+            for key in keys {
+                match (old.get(key), model.get(key)) {
+                    (None, Some(pair)) => expected.push(Diff::Added(pair.clone())),
+                    (Some(pair), None) => expected.push(Diff::Removed(pair.clone())),
+                    (Some(a), Some(b)) if a != b => {
+                        expected.push(Diff::Changed(a.clone(), b.clone()))
+                    }
+                    _ => {}
+                }
+            }
+            assert_eq!(btree.diff(before, after)?, expected);
+
+            // Diffing the other way round swaps additions and removals.
+            let reversed: Vec<Diff> = expected
+                .into_iter()
+                .map(|diff| match diff {
+                    Diff::Added(pair) => Diff::Removed(pair),
+                    Diff::Removed(pair) => Diff::Added(pair),
+                    Diff::Changed(a, b) => Diff::Changed(b, a),
+                })
+                .collect();
+            assert_eq!(btree.diff(after, before)?, reversed);
+
+            // Every pair was added since the empty tree.
+            let added: Vec<Diff> = model.into_values().map(Diff::Added).collect();
+            assert_eq!(btree.diff(0, after)?, added);
+            assert!(btree.diff(after, after)?.is_empty());
+            assert!(matches!(btree.diff(0, after + 1), Err(Error::RootNotFound)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn diff_skips_untouched_sub_trees() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::change::Diff;
+        use crate::fault_injection::{Crash, FaultyStorage};
+        use crate::node_type::KeyValuePair;
+
+        for subtree_counts in [true, false] {
+            // Only the reads of the tree's pages are counted, the WAL has its own counter.
+            let reads = Crash::new(None);
+            let mut btree = BTreeBuilder::new()
+                .b_parameter(2)
+                .subtree_counts(subtree_counts)
+                .build_with_storage(
+                    FaultyStorage::new(reads.clone(), vec![]),
+                    FaultyStorage::new(Crash::new(None), vec![]),
+                )?;
+            let keys: Vec<[u8; 16]> = (0..500).map(|_| Uuid::new_v4().into_bytes()).collect();
+            for key in keys.iter() {
+                btree.insert(KeyValuePair::new(*key, "shalom".to_string()))?;
+            }
+            let before = btree.sequence()?;
+            btree.insert(KeyValuePair::new(keys[7], "shalom!".to_string()))?;
+            let after = btree.sequence()?;
+
+            let start = reads.borrow().reads();
+            let diff = btree.diff(before, after)?;
+            let diffed = reads.borrow().reads() - start;
+            assert_eq!(
+                diff,
+                vec![Diff::Changed(
+                    KeyValuePair::new(keys[7], "shalom".to_string()),
+                    KeyValuePair::new(keys[7], "shalom!".to_string()),
+                )]
+            );
+
+            // Diffing against the empty tree reads every node of the later version, while only
+            // the paths down to the rewritten leaf were read above.
+            let start = reads.borrow().reads();
+            assert_eq!(btree.diff(0, after)?.len(), keys.len());
+            let scanned = reads.borrow().reads() - start;
+            assert!(diffed * 10 < scanned, "{} reads out of {}", diffed, scanned);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn backups_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::page_layout::PAGE_SIZE;
+
+        let path = Path::new("/tmp/backups_work/db");
+        let backup = Path::new("/tmp/backups_work/backup");
+        clean(path)?;
+        clean(backup)?;
+
+        let mut writer = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        let keys: Vec<[u8; 16]> = (0..300).map(|_| Uuid::now_v7().into_bytes()).collect();
+        for key in keys.iter() {
+            writer.insert(KeyValuePair::new(*key, "value".to_string()))?;
+        }
+        for key in keys.iter().step_by(3) {
+            writer.delete(Key(*key))?;
+        }
+
+        // A reader backs the tree up while its writer is still around, copying a single version
+        // of every reachable page rather than every page ever written.
+        let mut reader = BTreeBuilder::new().path(path).read_only(true).build()?;
+        let sequence = reader.backup_to(backup)?;
+        let backed_up = fs::metadata(backup)?.len() as usize / PAGE_SIZE;
+        let written = fs::metadata(path)?.len() as usize / PAGE_SIZE;
+        assert!(
+            backed_up * 2 < written,
+            "{} of {} pages backed up",
+            backed_up,
+            written
+        );
+        let mut copy = BTreeBuilder::new().path(backup).b_parameter(2).build()?;
+        assert_eq!(copy.range(..)?, writer.range(..)?);
+        drop(copy);
+
+        // Incremental backups only copy the pages written since.
+        let more: Vec<[u8; 16]> = (0..3).map(|_| Uuid::now_v7().into_bytes()).collect();
+        for key in more.iter() {
+            writer.insert(KeyValuePair::new(*key, "more".to_string()))?;
+        }
+        writer.delete(Key(keys[1]))?;
+        let latest = reader.incremental_backup_to(backup, sequence)?;
+        assert_eq!(latest, writer.sequence()?);
+        let copied = fs::metadata(backup)?.len() as usize / PAGE_SIZE - backed_up;
+        assert!(copied > 0 && copied < 10, "{} pages copied", copied);
+        let mut copy = BTreeBuilder::new().path(backup).b_parameter(2).build()?;
+        assert_eq!(copy.range(..)?, writer.range(..)?);
+        assert_eq!(copy.search(&more[2])?.value, "more");
+        drop(copy);
+
+        // An incremental backup has to start from the commit the backup was last brought up to,
+        // rather than from any commit sharing its root.
+        writer.delete(Key(keys[2]))?;
+        for since in [1, latest - 1, latest + 1] {
+            assert!(matches!(
+                reader.incremental_backup_to(backup, since),
+                Err(Error::Conflict)
+            ));
+        }
+        assert_eq!(reader.incremental_backup_to(backup, latest)?, latest + 1);
+        let mut copy = BTreeBuilder::new().path(backup).b_parameter(2).build()?;
+        assert_eq!(copy.range(..)?, writer.range(..)?);
+        assert!(copy.changes_since(0)?.is_empty());
+        drop(copy);
+
+        // Open trees are not overwritten.
+        assert!(matches!(reader.backup_to(path), Err(Error::Locked)));
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_are_linked_in_both_directions() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair, NodeType};
+
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
+        let keys: Vec<[u8; 16]> = (0..80).map(|_| Uuid::now_v7().into_bytes()).collect();
+        for key in keys.iter() {
+            btree.insert(KeyValuePair::new(*key, "value".to_string()))?;
+        }
+        for key in keys.iter().skip(1).step_by(2) {
+            btree.delete(Key(*key))?;
+        }
+
+        // Find the first and the last leaves from the root.
+        let root_id = btree.root()?;
+        let (mut first, mut last) = (root_id, root_id);
+        while let NodeType::Internal(children, _, _) = btree.get_node(&first)?.node_type {
+            first = children[0];
+        }
+        while let NodeType::Internal(children, _, _) = btree.get_node(&last)?.node_type {
+            last = children[children.len() - 1];
+        }
+
+        // Walking the leaves forwards visits the keys in order, walking them backwards
+        // visits the very same leaves.
+        let mut forwards = vec![];
+        let mut pairs = vec![];
+        let mut next = Some(first);
+        while let Some(id) = next {
+            let node = btree.get_node(&id)?;
+            if let NodeType::Leaf(leaf_pairs) = node.node_type {
+                pairs.extend(leaf_pairs.into_iter().map(|pair| pair.key));
+            }
+            forwards.push(id);
+            next = node.next;
+        }
+        let mut backwards = vec![];
+        let mut prev = Some(last);
+        while let Some(id) = prev {
+            backwards.push(id);
+            prev = btree.get_node(&id)?.prev;
+        }
+        backwards.reverse();
+        assert_eq!(forwards, backwards);
+        let expected: Vec<[u8; 16]> = keys.iter().step_by(2).cloned().collect();
+        assert_eq!(pairs, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_do_not_cascade_to_the_root() -> Result<(), Error> {
+        use crate::btree::{BTree, BTreeBuilder};
+        use crate::node_type::{Key, KeyValuePair, NodeType};
+        use crate::page_layout::PAGE_SIZE;
+        use crate::storage::MemoryStorage;
+
+        let mut btree = BTreeBuilder::in_memory().b_parameter(5).build()?;
+        let keys: Vec<[u8; 16]> = (0..1000).map(|_| Uuid::now_v7().into_bytes()).collect();
+        let pages =
+            |btree: &BTree<MemoryStorage>| btree.pager.storage().as_bytes().len() / PAGE_SIZE;
+
+        let before = pages(&btree);
+        for key in keys.iter() {
+            btree.insert(KeyValuePair::new(*key, "value".to_string()))?;
+        }
+        let inserted = pages(&btree) - before;
+        let before = pages(&btree);
+        for key in keys.iter().step_by(2) {
+            btree.delete(Key(*key))?;
+        }
+        let deleted = pages(&btree) - before;
+
+        // The tree is at least three levels deep.
+        let root_id = btree.root()?;
+        let root = btree.get_node(&root_id)?;
+        let child = match root.node_type {
+            NodeType::Internal(children, _, _) => btree.get_node(&children[0])?,
+            _ => return Err(Error::UnexpectedError),
+        };
+        assert!(matches!(child.node_type, NodeType::Internal(..)));
+
+        // Had every write rewritten the path from the leaf up to the root,
+        // at least three pages would have been written per write. Splits and merges
+        // account for the pages written besides the leaves.
+        assert!(inserted < 2 * keys.len());
+        assert!(deleted < 3 * keys.len() / 2);
+        Ok(())
+    }
+
+    #[test]
+    fn trees_without_page_table_work() -> Result<(), Error> {
+        use crate::btree::{BTreeBuilder, Op};
+        use crate::change::Diff;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::collections::BTreeMap;
+
+        for subtree_counts in [true, false] {
+            let path = Path::new("/tmp/trees_without_page_table_work/db");
+            let backup = Path::new("/tmp/trees_without_page_table_work/backup");
+            clean(path)?;
+            clean(backup)?;
+            let mut btree = BTreeBuilder::new()
+                .path(path)
+                .b_parameter(2)
+                .subtree_counts(subtree_counts)
+                .page_table(false)
+                .build()?;
+            let mut model = BTreeMap::new();
+            for i in 0..300 {
+                let key = Uuid::new_v4().into_bytes();
+                btree.insert(KeyValuePair::new(key, i.to_string()))?;
+                model.insert(key, i.to_string());
+            }
+            let before = btree.sequence()?;
+            let old = model.clone();
+            let sequence = btree.backup_to(backup)?;
+
+            let keys: Vec<[u8; 16]> = model.keys().cloned().collect();
+            for key in keys.iter().step_by(7) {
+                btree.delete(Key(*key))?;
+                model.remove(key);
+            }
+            let ops = keys
+                .iter()
+                .skip(1)
+                .step_by(5)
+                .map(|key| Op::Insert(KeyValuePair::new(*key, "batch".to_string())))
+                .collect();
+            btree.apply_batch(ops)?;
+            for key in keys.iter().skip(1).step_by(5) {
+                model.insert(*key, "batch".to_string());
+            }
+            btree.compare_and_swap(Key(keys[2]), Some(model[&keys[2]].clone()), None)?;
+            model.remove(&keys[2]);
+            btree.delete_range(Key(keys[50])..Key(keys[120]))?;
+            model.retain(|key, _| !(keys[50]..keys[120]).contains(key));
+            let after = btree.sequence()?;
+
+            // Leaves are not linked, scans and lookups step from leaf to leaf in either
+            // direction along the path from the root.
+            let pairs: Vec<KeyValuePair> = model
+                .iter()
+                .map(|(key, value)| KeyValuePair::new(*key, value.clone()))
+                .collect();
+            assert_eq!(btree.range(..)?, pairs);
+            assert_eq!(btree.range(Key(keys[10])..Key(keys[200]))?.len(), {
+                model.range(keys[10]..keys[200]).count()
+            });
+            let mut backwards = vec![btree.last()?];
+            while let Ok(pair) = btree.lower(&Key(backwards[backwards.len() - 1].key)) {
+                backwards.push(pair);
+            }
+            backwards.reverse();
+            assert_eq!(backwards, pairs);
+            assert_eq!(btree.len()?, model.len());
+
+            // Pages are found by their ids alone, so no commit lists any page.
+            assert!(btree
+                .wal
+                .records_since(0)?
+                .iter()
+                .all(|r| r.pages.is_empty()));
+
+            // Committed pages are never overwritten, past versions can still be compared.
+            let mut expected = Vec::new();
+            for (key, value) in old.iter() {
+                let pair = KeyValuePair::new(*key, value.clone());
+                match model.get(key) {
+                    None => expected.push(Diff::Removed(pair)),
+                    Some(new) if new != value => {
+                        expected.push(Diff::Changed(pair, KeyValuePair::new(*key, new.clone())))
+                    }
+                    _ => {}
+                }
+            }
+            assert_eq!(btree.diff(before, after)?, expected);
+
+            // Backups copy the pages written since the last one at the offsets they are found at.
+            btree.incremental_backup_to(backup, sequence)?;
+            let mut copy = BTreeBuilder::new().path(backup).b_parameter(2).build()?;
+            assert_eq!(copy.range(..)?, pairs);
+            drop(copy);
+
+            // The tree goes on without a page table once reopened, whatever is asked.
+            drop(btree);
+            let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+            assert!(!btree.pager.has_page_table());
+            assert_eq!(btree.range(..)?, pairs);
+            btree.insert(KeyValuePair::new(keys[0], "again".to_string()))?;
+            assert_eq!(btree.search(&keys[0])?.value, "again");
+            assert!(btree
+                .wal
+                .records_since(0)?
+                .iter()
+                .all(|r| r.pages.is_empty()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn nodes_split_as_pages_fill_up() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use std::collections::BTreeMap;
+
+        // 2b pairs would not fit in a page, nodes are split as their pages fill up instead.
+        let mut btree = BTreeBuilder::in_memory().b_parameter(200).build()?;
+        let mut model = BTreeMap::new();
+        // Random keys share no prefix, timestamped ones share most of it.
+        for i in 0..3000 {
+            let key = match i % 2 {
+                0 => Uuid::new_v4().into_bytes(),
+                _ => Uuid::now_v7().into_bytes(),
+            };
+            btree.insert(KeyValuePair::new(key, i.to_string()))?;
+            model.insert(key, i.to_string());
+        }
+        let keys: Vec<[u8; 16]> = model.keys().cloned().collect();
+        for key in keys.iter().step_by(3).chain(keys.iter().skip(1).step_by(3)) {
+            btree.delete(Key(*key))?;
+            model.remove(key);
+        }
+
+        let pairs = btree.range(..)?;
+        assert_eq!(pairs.len(), model.len());
+        for (pair, (key, value)) in pairs.iter().zip(model.iter()) {
+            assert_eq!(pair.key, *key);
+            assert_eq!(pair.value, *value);
+        }
+        for key in model.keys() {
+            btree.search(key)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_trees_work() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::cipher::{ENCRYPTED_PAGE_SIZE, NONCE_SIZE};
+        use crate::node_type::KeyValuePair;
+        use std::collections::HashSet;
+
+        let path = Path::new("/tmp/encrypted_trees_work/db");
+        clean(path)?;
+
+        let key = Uuid::now_v7().into_bytes();
+        let mut btree = BTreeBuilder::in_memory()
+            .b_parameter(2)
+            .encryption_key([0x07; 32])
+            .build()?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(
+                Uuid::now_v7().into_bytes(),
+                format!("secret{}", i),
+            ))?;
+        }
+        btree.insert(KeyValuePair::new(key, "secret".to_string()))?;
+        btree.dump(path)?;
+        drop(btree);
+
+        // Neither keys nor values are stored in plaintext, in the tree or in the changes logged
+        // to the WAL.
+        let data = fs::read(path)?;
+        assert!(!data.windows(6).any(|window| window == b"secret"));
+        assert!(!data.windows(16).any(|window| window == key));
+        let wal = fs::read(super::default_wal_path(path)?)?;
+        assert!(!wal.windows(6).any(|window| window == b"secret"));
+        assert!(!wal.windows(16).any(|window| window == key));
+
+        let mut btree = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(2)
+            .encryption_key([0x07; 32])
+            .build()?;
+        assert_eq!(btree.search(&key)?.value, "secret");
+        assert_eq!(btree.changes_since(0)?.len(), 21);
+
+        // Backups are encrypted under the same key.
+        let backup = Path::new("/tmp/encrypted_trees_work/backup");
+        clean(backup)?;
+        btree.backup_to(backup)?;
+        let backup_data = fs::read(backup)?;
+        assert!(!backup_data.windows(6).any(|window| window == b"secret"));
+        let mut copy = BTreeBuilder::new()
+            .path(backup)
+            .b_parameter(2)
+            .encryption_key([0x07; 32])
+            .build()?;
+        assert_eq!(copy.search(&key)?.value, "secret");
+        drop(copy);
+        drop(btree);
+
+        // Pages copied over to the backup are encrypted under nonces of their own.
+        let nonces = |data: &[u8]| -> HashSet<Vec<u8>> {
+            data.chunks(ENCRYPTED_PAGE_SIZE)
+                .map(|page| page[..NONCE_SIZE].to_vec())
+                .collect()
+        };
+        assert!(nonces(&data).is_disjoint(&nonces(&backup_data)));
+
+        // Another key fails to read the tree.
+        let res = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(2)
+            .encryption_key([0x08; 32])
+            .build()
+            .and_then(|mut btree| btree.search(&key));
+        assert!(matches!(res, Err(Error::Corrupted)));
+
+        // So does tampering with any of its pages.
+        let mut tampered = data.clone();
+        for byte in tampered.iter_mut() {
+            *byte ^= 0x01;
+        }
+        fs::write(path, tampered)?;
+        let res = BTreeBuilder::new()
+            .path(path)
+            .b_parameter(2)
+            .encryption_key([0x07; 32])
+            .build()
+            .and_then(|mut btree| btree.search(&key));
+        assert!(matches!(res, Err(Error::Corrupted)));
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn tampered_page_tables_are_detected() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use crate::storage::{FileStorage, Storage};
+        use crate::wal::{Wal, WalRecord};
+
+        let path = Path::new("/tmp/tampered_page_tables_are_detected/db");
+        clean(path)?;
+        let wal_path = super::default_wal_path(path)?;
+        let open = || {
+            BTreeBuilder::new()
+                .path(path)
+                .b_parameter(2)
+                .encryption_key([0x07; 32])
+                .build()
+        };
+        let mut btree = open()?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(
+                Uuid::now_v7().into_bytes(),
+                format!("value{}", i),
+            ))?;
+        }
+        drop(btree);
+
+        let records = Wal::new(FileStorage::new(&wal_path)?)?.records_since(0)?;
+        // rewrite logs the given records anew, with valid checksums.
+        let rewrite = |records: &[WalRecord]| -> Result<(), Error> {
+            let mut storage = FileStorage::new(&wal_path)?;
+            storage.truncate(0)?;
+            let mut wal = Wal::new(storage)?;
+            for record in records {
+                wal.commit(record.root, record.pages.clone(), record.metadata.clone())?;
+            }
+            Ok(())
+        };
+        rewrite(&records)?;
+        assert_eq!(open()?.len()?, 30);
+
+        // Pointing a page at an older copy of itself.
+        let last = records.len() - 1;
+        let (id, offset, _) = records[last].pages[0].clone();
+        let older = records[..last]
+            .iter()
+            .flat_map(|record| record.pages.iter())
+            .find(|(older_id, older_offset, _)| *older_id == id && *older_offset != offset)
+            .ok_or(Error::UnexpectedError)?
+            .1
+            .clone();
+        let mut replayed = records.clone();
+        replayed[last].pages[0].1 = older;
+        rewrite(&replayed)?;
+        assert!(matches!(open(), Err(Error::Corrupted)));
+
+        // Swapping the pages of two ids written by the same commit.
+        let split = records
+            .iter()
+            .rposition(|record| record.pages.len() > 1)
+            .ok_or(Error::UnexpectedError)?;
+        let mut swapped = records.clone();
+        let pages = &mut swapped[split].pages;
+        let offset = pages[0].1.clone();
+        pages[0].1 = pages[1].1.clone();
+        pages[1].1 = offset;
+        rewrite(&swapped)?;
+        assert!(matches!(open(), Err(Error::Corrupted)));
+
+        // Dropping the metadata of a record.
+        let mut stripped = records.clone();
+        stripped[last].metadata.clear();
+        rewrite(&stripped)?;
+        assert!(matches!(open(), Err(Error::Corrupted)));
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_recovers_tree() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+
+        let path = Path::new("/tmp/reopen_recovers_tree/db");
+        clean(path)?;
+
+        let a = Uuid::now_v7();
+        let b = Uuid::now_v7();
+        let c = Uuid::now_v7();
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        btree.insert(KeyValuePair::new(a.into_bytes(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new(b.into_bytes(), "hello".to_string()))?;
+        btree.insert(KeyValuePair::new(c.into_bytes(), "marhaba".to_string()))?;
+        btree.delete(Key(b.into_bytes()))?;
+        drop(btree);
+
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        let kv = btree.search(&a.into_bytes())?;
+        assert_eq!(kv.value, "shalom");
+        let kv = btree.search(&c.into_bytes())?;
+        assert_eq!(kv.value, "marhaba");
+        let res = btree.search(&b.into_bytes());
+        assert!(matches!(res, Err(Error::KeyNotFound)));
+
+        // The reopened tree keeps appending after the existing pages.
+        let d = Uuid::now_v7();
+        btree.insert(KeyValuePair::new(d.into_bytes(), "olah".to_string()))?;
+        let kv = btree.search(&d.into_bytes())?;
+        assert_eq!(kv.value, "olah");
+        Ok(())
+    }
+
+    #[test]
+    fn custom_storage_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+        use crate::storage::{MemoryStorage, MmapStorage};
+
+        let a = Uuid::now_v7();
+        let b = Uuid::now_v7();
+        let mut btree = BTreeBuilder::new()
+            .b_parameter(2)
+            .build_with_storage(MemoryStorage::new(), MemoryStorage::new())?;
+        btree.insert(KeyValuePair::new(a.into_bytes(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new(b.into_bytes(), "hello".to_string()))?;
+        btree.delete(Key(a.into_bytes()))?;
+        let res = btree.search(&a.into_bytes());
+        assert!(matches!(res, Err(Error::KeyNotFound)));
+        assert_eq!(btree.search(&b.into_bytes())?.value, "hello");
+
+        let path = Path::new("/tmp/custom_storage_works/db");
+        clean(path)?;
+        let wal_path = Path::new("/tmp/custom_storage_works/db-wal");
+        let mut btree = BTreeBuilder::new()
+            .b_parameter(2)
+            .build_with_storage(MmapStorage::new(path)?, MmapStorage::new(wal_path)?)?;
+        btree.insert(KeyValuePair::new(a.into_bytes(), "shalom".to_string()))?;
+        drop(btree);
+        let mut btree = BTreeBuilder::new()
+            .b_parameter(2)
+            .build_with_storage(MmapStorage::new(path)?, MmapStorage::new(wal_path)?)?;
+        assert_eq!(btree.search(&a.into_bytes())?.value, "shalom");
+        Ok(())
+    }
+
+    #[test]
+    fn dump_in_memory_tree_works() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+
+        let path = Path::new("/tmp/dump_in_memory_tree_works/db");
+        clean(path)?;
+
+        let a = Uuid::now_v7();
+        let b = Uuid::now_v7();
+        let mut btree = BTreeBuilder::in_memory().b_parameter(2).build()?;
+        btree.insert(KeyValuePair::new(a.into_bytes(), "shalom".to_string()))?;
+        btree.insert(KeyValuePair::new(b.into_bytes(), "hello".to_string()))?;
+        btree.dump(path)?;
+
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        assert_eq!(btree.search(&a.into_bytes())?.value, "shalom");
+        assert_eq!(btree.search(&b.into_bytes())?.value, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn trees_in_same_directory_do_not_share_wal() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+
+        let directory = Path::new("/tmp/trees_in_same_directory_do_not_share_wal");
+        let first_path = directory.join("db");
+        let second_path = directory.join("other");
+        let wal_path = directory.join("explicit.wal");
+        clean(&first_path)?;
+        let _ = fs::remove_file(&second_path);
+        let _ = fs::remove_file(&wal_path);
+
+        let a = Uuid::now_v7();
+        let b = Uuid::now_v7();
+        let mut first = BTreeBuilder::new()
+            .path(&first_path)
+            .b_parameter(2)
+            .build()?;
+        let mut second = BTreeBuilder::new()
+            .path(&second_path)
+            .wal_path(&wal_path)
+            .b_parameter(2)
+            .build()?;
+        first.insert(KeyValuePair::new(a.into_bytes(), "shalom".to_string()))?;
+        second.insert(KeyValuePair::new(b.into_bytes(), "hello".to_string()))?;
+        assert!(directory.join("db-wal").exists());
+        assert!(wal_path.exists());
+
+        assert_eq!(first.search(&a.into_bytes())?.value, "shalom");
+        let res = first.search(&b.into_bytes());
+        assert!(matches!(res, Err(Error::KeyNotFound)));
+        assert_eq!(second.search(&b.into_bytes())?.value, "hello");
+        let res = second.search(&a.into_bytes());
+        assert!(matches!(res, Err(Error::KeyNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn tree_is_locked_while_open() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+
+        let path = Path::new("/tmp/tree_is_locked_while_open/db");
+        clean(path)?;
+
+        let btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        let res = BTreeBuilder::new().path(path).b_parameter(2).build();
+        assert!(matches!(res, Err(Error::Locked)));
+        // Nor can a second writer open the tree file with a WAL of its own.
+        let other_wal = Path::new("/tmp/tree_is_locked_while_open/other-wal");
+        let _ = fs::remove_file(other_wal);
+        let res = BTreeBuilder::new()
+            .path(path)
+            .wal_path(other_wal)
+            .b_parameter(2)
+            .build();
+        assert!(matches!(res, Err(Error::Locked)));
+        let res = BTreeBuilder::in_memory().b_parameter(2).build()?.dump(path);
+        assert!(matches!(res, Err(Error::Locked)));
+
+        drop(btree);
+        BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        Ok(())
+    }
+
+    #[test]
+    fn readers_follow_writer() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::{Key, KeyValuePair};
+
+        let path = Path::new("/tmp/readers_follow_writer/db");
+        clean(path)?;
+
+        // A reader cannot open a tree which does not exist.
+        let res = BTreeBuilder::new().path(path).read_only(true).build();
+        assert!(res.is_err());
+        assert!(!path.exists());
+
+        let mut writer = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        let first = Uuid::now_v7().into_bytes();
+        writer.insert(KeyValuePair::new(first, "first".to_string()))?;
+
+        // Readers coexist with the writer, and with each other.
+        let mut reader = BTreeBuilder::new().path(path).read_only(true).build()?;
+        let mut other = BTreeBuilder::new().path(path).read_only(true).build()?;
+        assert_eq!(reader.search(&first)?.value, "first");
+
+        // Commits of the writer are picked up by open readers.
+        let second = Uuid::now_v7().into_bytes();
+        writer.insert(KeyValuePair::new(second, "second".to_string()))?;
+        writer.delete(Key(first))?;
+        assert_eq!(reader.search(&second)?.value, "second");
+        assert!(matches!(reader.search(&first), Err(Error::KeyNotFound)));
+        assert_eq!(other.search(&second)?.value, "second");
+
+        // Readers cannot write.
+        let res = reader.insert(KeyValuePair::new(first, "first".to_string()));
+        assert!(matches!(res, Err(Error::ReadOnly)));
+        assert!(matches!(reader.delete(Key(second)), Err(Error::ReadOnly)));
+
+        // The tree cannot be overwritten while readers are around.
+        drop(writer);
+        let res = BTreeBuilder::in_memory().b_parameter(2).build()?.dump(path);
+        assert!(matches!(res, Err(Error::Locked)));
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_opens_snapshot_copies() -> Result<(), Error> {
+        use crate::btree::BTreeBuilder;
+        use crate::node_type::KeyValuePair;
+        use std::io::Write;
+
+        let path = Path::new("/tmp/read_only_opens_snapshot_copies/db");
+        let snapshot = Path::new("/tmp/read_only_opens_snapshot_copies/snapshot");
+        let snapshot_wal = Path::new("/tmp/read_only_opens_snapshot_copies/snapshot-wal");
+        clean(path)?;
+        clean(snapshot)?;
+
+        let mut btree = BTreeBuilder::new().path(path).b_parameter(2).build()?;
+        let keys: Vec<[u8; 16]> = (0..10).map(|_| Uuid::now_v7().into_bytes()).collect();
+        for key in keys.iter() {
+            btree.insert(KeyValuePair::new(*key, "value".to_string()))?;
+        }
+
+        // Copy the tree while it is being written to, leaving a torn record at the end of the WAL.
+        fs::copy("/tmp/read_only_opens_snapshot_copies/db-wal", snapshot_wal)?;
+        fs::copy(path, snapshot)?;
+        let mut wal = fs::OpenOptions::new().append(true).open(snapshot_wal)?;
+        wal.write_all(&[0x00, 0x00, 0x00])?;
+        drop(wal);
+        for file in [snapshot, snapshot_wal] {
+            let mut permissions = fs::metadata(file)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(file, permissions)?;
+        }
+        let tree_before = fs::read(snapshot)?;
+        let wal_before = fs::read(snapshot_wal)?;
+
+        let mut reader = BTreeBuilder::new().path(snapshot).read_only(true).build()?;
+        for key in keys.iter() {
+            assert_eq!(reader.search(key)?.value, "value");
+        }
+        reader.print()?;
+        let res = reader.insert(KeyValuePair::new(keys[0], "other".to_string()));
+        assert!(matches!(res, Err(Error::ReadOnly)));
+        drop(reader);
+
+        // The snapshot is left untouched.
+        assert_eq!(fs::read(snapshot)?, tree_before);
+        assert_eq!(fs::read(snapshot_wal)?, wal_before);
+        Ok(())
+    }
 }
@@ -0,0 +1,244 @@
+use crate::error::Error;
+use crate::node_type::{Key, KeyValuePair};
+use crate::page_layout::{KEY_SIZE, PTR_SIZE};
+use std::convert::TryFrom;
+use std::ops::Bound;
+
+/// Change record layout, logged as the metadata of a commit
+///
+/// | Kind - 1 byte | Number of changes - 8 bytes | Change #0 | Change #1 | ...
+///
+/// The commits made by backups rather than by writes are of another kind, and carry the
+/// sequence number of the commit backed up instead of changes:
+///
+/// | Kind - 1 byte | Sequence number - 8 bytes |
+///
+/// Each change starts with its kind - 1 byte, followed by:
+/// - a put: | Key - 16 bytes | Expires - 1 byte | Expiry - 8 bytes | Value length - 8 bytes | Value |
+/// - a delete: | Key - 16 bytes |
+/// - a range delete: | Start bound - 1 byte | Start key - 16 bytes | End bound - 1 byte | End key - 16 bytes |
+///
+/// A pair which does not expire has its expires flag cleared and an expiry of zero, and the key of an unbounded end is all zeros.
+const CHANGES: u8 = 0x00;
+const BACKUP: u8 = 0x01;
+
+const PUT: u8 = 0x00;
+const DELETE: u8 = 0x01;
+const DELETE_RANGE: u8 = 0x02;
+
+const UNBOUNDED: u8 = 0x00;
+const INCLUDED: u8 = 0x01;
+const EXCLUDED: u8 = 0x02;
+
+/// Change is a write to the pairs of the tree, as published to its subscribers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// A key value pair was inserted or updated.
+    Put(KeyValuePair),
+    /// A key was deleted.
+    Delete(Key),
+    /// Every key within a range was deleted.
+    DeleteRange(Bound<Key>, Bound<Key>),
+}
+
+/// ChangeSet is the changes made by a single commit, in the order in which they were applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeSet {
+    /// The sequence number of the commit in the WAL.
+    pub sequence: u64,
+    pub changes: Vec<Change>,
+}
+
+/// Diff is a difference between the pairs of two versions of the tree, see `BTree::diff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Diff {
+    /// A pair found in the later version only.
+    Added(KeyValuePair),
+    /// A pair found in the earlier version only.
+    Removed(KeyValuePair),
+    /// A key whose pair differs between the versions, given as of the earlier and the later version.
+    Changed(KeyValuePair, KeyValuePair),
+}
+
+/// encode serializes the changes of a commit.
+pub fn encode(changes: &[Change]) -> Vec<u8> {
+    let mut data = vec![CHANGES];
+    data.extend_from_slice(&changes.len().to_be_bytes());
+    for change in changes {
+        match change {
+            Change::Put(kv) => {
+                data.push(PUT);
+                data.extend_from_slice(&kv.key);
+                data.push(u8::from(kv.expires_at.is_some()));
+                data.extend_from_slice(&kv.expires_at.unwrap_or(0).to_be_bytes());
+                data.extend_from_slice(&kv.value.len().to_be_bytes());
+                data.extend_from_slice(kv.value.as_bytes());
+            }
+            Change::Delete(key) => {
+                data.push(DELETE);
+                data.extend_from_slice(&key.0);
+            }
+            Change::DeleteRange(start, end) => {
+                data.push(DELETE_RANGE);
+                for bound in [start, end] {
+                    let (kind, key) = match bound {
+                        Bound::Unbounded => (UNBOUNDED, [0x00; KEY_SIZE]),
+                        Bound::Included(key) => (INCLUDED, key.0),
+                        Bound::Excluded(key) => (EXCLUDED, key.0),
+                    };
+                    data.push(kind);
+                    data.extend_from_slice(&key);
+                }
+            }
+        }
+    }
+    data
+}
+
+/// encode_backup serializes the metadata of a commit made by a backup of the tree,
+/// given the sequence number of the commit of the tree it was brought up to.
+pub fn encode_backup(sequence: u64) -> Vec<u8> {
+    let mut data = vec![BACKUP];
+    data.extend_from_slice(&sequence.to_be_bytes());
+    data
+}
+
+/// decode deserializes the changes of a commit, failing with `Error::Corrupted` if they are malformed.
+/// Commits which changed no pair may log no metadata at all, and commits made by backups list
+/// no changes.
+pub fn decode(data: &[u8]) -> Result<Vec<Change>, Error> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut reader = Reader { data, offset: 0 };
+    match reader.byte()? {
+        CHANGES => {}
+        BACKUP => return Ok(Vec::new()),
+        _ => return Err(Error::Corrupted),
+    }
+    let num_changes = reader.usize()?;
+    let mut changes = Vec::new();
+    for _ in 0..num_changes {
+        let change = match reader.byte()? {
+            PUT => {
+                let key = reader.key()?;
+                let expires = match reader.byte()? {
+                    0x00 => false,
+                    0x01 => true,
+                    _ => return Err(Error::Corrupted),
+                };
+                let expires_at = reader.u64()?;
+                let len = reader.usize()?;
+                let value =
+                    String::from_utf8(reader.bytes(len)?.to_vec()).map_err(|_| Error::Corrupted)?;
+                Change::Put(KeyValuePair {
+                    key,
+                    value,
+                    expires_at: Some(expires_at).filter(|_| expires),
+                })
+            }
+            DELETE => Change::Delete(Key(reader.key()?)),
+            DELETE_RANGE => Change::DeleteRange(reader.bound()?, reader.bound()?),
+            _ => return Err(Error::Corrupted),
+        };
+        changes.push(change);
+    }
+    Ok(changes)
+}
+
+/// decode_backup returns the sequence number of the commit of the tree a backup was brought up
+/// to, given the metadata of a commit of the backup, or None if it was not made by a backup.
+pub fn decode_backup(data: &[u8]) -> Result<Option<u64>, Error> {
+    let mut reader = Reader { data, offset: 0 };
+    match reader.byte()? {
+        BACKUP => Ok(Some(reader.u64()?)),
+        _ => Ok(None),
+    }
+}
+
+/// Reader reads the fields of encoded changes one after the other.
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], Error> {
+        let end = self.offset.checked_add(len).ok_or(Error::Corrupted)?;
+        let bytes = self.data.get(self.offset..end).ok_or(Error::Corrupted)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let bytes = <[u8; 8]>::try_from(self.bytes(8)?).map_err(|_| Error::Corrupted)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn usize(&mut self) -> Result<usize, Error> {
+        let bytes =
+            <[u8; PTR_SIZE]>::try_from(self.bytes(PTR_SIZE)?).map_err(|_| Error::Corrupted)?;
+        Ok(usize::from_be_bytes(bytes))
+    }
+
+    fn key(&mut self) -> Result<[u8; KEY_SIZE], Error> {
+        <[u8; KEY_SIZE]>::try_from(self.bytes(KEY_SIZE)?).map_err(|_| Error::Corrupted)
+    }
+
+    fn bound(&mut self) -> Result<Bound<Key>, Error> {
+        let kind = self.byte()?;
+        let key = Key(self.key()?);
+        match kind {
+            UNBOUNDED => Ok(Bound::Unbounded),
+            INCLUDED => Ok(Bound::Included(key)),
+            EXCLUDED => Ok(Bound::Excluded(key)),
+            _ => Err(Error::Corrupted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::change::{decode, decode_backup, encode, encode_backup, Change};
+    use crate::error::Error;
+    use crate::node_type::{Key, KeyValuePair};
+    use std::ops::Bound;
+    use uuid::Uuid;
+
+    #[test]
+    fn changes_round_trip() -> Result<(), Error> {
+        let a = Uuid::now_v7().into_bytes();
+        let b = Uuid::now_v7().into_bytes();
+        let changes = vec![
+            Change::Put(KeyValuePair::new(a, "shalom".to_string())),
+            Change::Put(KeyValuePair::with_expiry(
+                b,
+                "hello".to_string(),
+                1730000000000,
+            )),
+            Change::Put(KeyValuePair::with_expiry(a, "zero".to_string(), 0)),
+            Change::Delete(Key(a)),
+            Change::DeleteRange(Bound::Included(Key(a)), Bound::Unbounded),
+            Change::DeleteRange(Bound::Unbounded, Bound::Excluded(Key(b))),
+        ];
+        let data = encode(&changes);
+        assert_eq!(decode(&data)?, changes);
+
+        // Truncated changes are detected.
+        assert!(matches!(
+            decode(&data[..data.len() - 1]),
+            Err(Error::Corrupted)
+        ));
+
+        // Commits made by backups are told apart from writes.
+        assert_eq!(decode_backup(&data)?, None);
+        assert_eq!(decode_backup(&encode_backup(42))?, Some(42));
+        assert_eq!(decode(&encode_backup(42))?, vec![]);
+        assert_eq!(decode(&[])?, vec![]);
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use crate::error::Error;
+use crate::page_layout::PAGE_SIZE;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+/// Encrypted page layout
+///
+/// | Nonce - 24 bytes | Encrypted page - 4096 bytes | Authentication tag - 16 bytes |
+///
+/// Encrypted records share the same layout, but for the length of what is encrypted.
+pub const NONCE_SIZE: usize = 24;
+pub const TAG_SIZE: usize = 16;
+pub const ENCRYPTED_PAGE_SIZE: usize = NONCE_SIZE + PAGE_SIZE + TAG_SIZE;
+
+/// Size of the keys pages are encrypted with.
+pub const KEY_SIZE: usize = 32;
+
+/// Cipher encrypts pages with XChaCha20-Poly1305 under the key of a tree.
+///
+/// Every page is encrypted under a random nonce stored in front of it, which is large enough
+/// for nonces never to repeat under a key, however many trees and backups share it.
+/// The id of the page and the offset it is written at are authenticated alongside it,
+/// so that a page which is modified, moved to another offset, or passed off as another page
+/// fails to decrypt.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Cipher {
+        Cipher {
+            aead: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    /// encrypt encrypts the page with a given id written at a given offset.
+    pub fn encrypt(
+        &self,
+        page: &[u8; PAGE_SIZE],
+        id: usize,
+        offset: usize,
+    ) -> Result<Vec<u8>, Error> {
+        self.seal(page, &page_aad(id, offset))
+    }
+
+    /// decrypt decrypts the page with a given id read at a given offset.
+    /// Fails with `Error::Corrupted` if the page was tampered with.
+    pub fn decrypt(&self, data: &[u8], id: usize, offset: usize) -> Result<[u8; PAGE_SIZE], Error> {
+        if data.len() != ENCRYPTED_PAGE_SIZE {
+            return Err(Error::Corrupted);
+        }
+        let page = self.open(data, &page_aad(id, offset))?;
+        let mut res = [0x00; PAGE_SIZE];
+        res.copy_from_slice(&page);
+        Ok(res)
+    }
+
+    /// encrypt_record encrypts data logged to the WAL alongside a commit, authenticating
+    /// the rest of the record (its sequence number, root and pages) alongside it.
+    pub fn encrypt_record(&self, record: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        self.seal(record, aad)
+    }
+
+    /// decrypt_record decrypts data logged to the WAL alongside a commit.
+    /// Fails with `Error::Corrupted` if the record was tampered with.
+    pub fn decrypt_record(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(Error::Corrupted);
+        }
+        self.open(data, aad)
+    }
+
+    /// seal encrypts a message under a random nonce drawn from the OS, which is stored
+    /// in front of it.
+    fn seal(&self, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted = self
+            .aead
+            .encrypt(&nonce, Payload { msg, aad })
+            .map_err(|_| Error::UnexpectedError)?;
+        let mut data = nonce.to_vec();
+        data.extend(encrypted);
+        Ok(data)
+    }
+
+    /// open decrypts a message sealed along with its nonce.
+    fn open(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let (nonce, msg) = data.split_at(NONCE_SIZE);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), Payload { msg, aad })
+            .map_err(|_| Error::Corrupted)
+    }
+}
+
+/// page_aad returns the data authenticated alongside a page: its id followed by its offset.
+fn page_aad(id: usize, offset: usize) -> [u8; 16] {
+    let mut aad = [0x00; 16];
+    aad[..8].copy_from_slice(&(id as u64).to_be_bytes());
+    aad[8..].copy_from_slice(&(offset as u64).to_be_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cipher::{Cipher, ENCRYPTED_PAGE_SIZE};
+    use crate::error::Error;
+    use crate::page_layout::PAGE_SIZE;
+
+    #[test]
+    fn tampered_pages_fail_to_decrypt() -> Result<(), Error> {
+        let cipher = Cipher::new(&[0x07; 32]);
+        let mut page = [0x00; PAGE_SIZE];
+        page[..5].copy_from_slice(b"hello");
+
+        let data = cipher.encrypt(&page, 1, PAGE_SIZE)?;
+        assert_eq!(data.len(), ENCRYPTED_PAGE_SIZE);
+        assert!(!data.windows(5).any(|window| window == b"hello"));
+        assert_eq!(cipher.decrypt(&data, 1, PAGE_SIZE)?, page);
+
+        // The same page is encrypted under another nonce every time.
+        assert_ne!(cipher.encrypt(&page, 1, PAGE_SIZE)?, data);
+
+        // Flipping a bit, moving the page, passing it off as another page
+        // or using another key are all detected.
+        let mut flipped = data.clone();
+        flipped[100] ^= 0x01;
+        assert!(matches!(
+            cipher.decrypt(&flipped, 1, PAGE_SIZE),
+            Err(Error::Corrupted)
+        ));
+        assert!(matches!(cipher.decrypt(&data, 1, 0), Err(Error::Corrupted)));
+        assert!(matches!(
+            cipher.decrypt(&data, 2, PAGE_SIZE),
+            Err(Error::Corrupted)
+        ));
+        assert!(matches!(
+            Cipher::new(&[0x08; 32]).decrypt(&data, 1, PAGE_SIZE),
+            Err(Error::Corrupted)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_records_fail_to_decrypt() -> Result<(), Error> {
+        let cipher = Cipher::new(&[0x07; 32]);
+        let data = cipher.encrypt_record(b"hello", b"header")?;
+        assert!(!data.windows(5).any(|window| window == b"hello"));
+        assert_eq!(cipher.decrypt_record(&data, b"header")?, b"hello".to_vec());
+
+        // The same record is encrypted under another nonce every time.
+        assert_ne!(cipher.encrypt_record(b"hello", b"header")?, data);
+
+        let mut flipped = data.clone();
+        flipped[30] ^= 0x01;
+        assert!(matches!(
+            cipher.decrypt_record(&flipped, b"header"),
+            Err(Error::Corrupted)
+        ));
+        // The rest of the record is authenticated too.
+        assert!(matches!(
+            cipher.decrypt_record(&data, b"headed"),
+            Err(Error::Corrupted)
+        ));
+        assert!(matches!(
+            Cipher::new(&[0x08; 32]).decrypt_record(&data, b"header"),
+            Err(Error::Corrupted)
+        ));
+        Ok(())
+    }
+}
@@ -5,8 +5,14 @@ pub enum Error {
     UnexpectedError,
     KeyOverflowError,
     ValueOverflowError,
+    PageOverflowError,
     TryFromSliceError(&'static str),
     UTF8Error,
+    RootNotFound,
+    Locked,
+    ReadOnly,
+    Corrupted,
+    Conflict,
 }
 
 impl std::convert::From<std::io::Error> for Error {
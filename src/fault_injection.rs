@@ -0,0 +1,403 @@
+use crate::error::Error;
+use crate::storage::Storage;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Rng is a small xorshift generator, so that every crash scenario is reproducible from its seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// below returns a number in [0, n), n has to be positive.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// FaultMode decides the fate of the writes which were not synced yet when a crash happened.
+#[derive(Clone, Copy, Debug)]
+pub enum FaultMode {
+    /// Each unsynced write is independently either persisted or lost.
+    Drop,
+    /// Unsynced writes are persisted in an arbitrary order, up to an arbitrary point.
+    Reorder,
+    /// Unsynced writes are persisted in order, but the write that crashed is only partially persisted.
+    Tear,
+}
+
+/// Crash counts the reads and writes issued to all of the storages sharing it,
+/// and crashes all of them once a given write index is reached.
+#[derive(Default)]
+pub struct Crash {
+    reads: usize,
+    writes: usize,
+    crash_at: Option<usize>,
+    crashed: bool,
+}
+
+impl Crash {
+    pub fn new(crash_at: Option<usize>) -> Rc<RefCell<Crash>> {
+        Rc::new(RefCell::new(Crash {
+            crash_at,
+            ..Crash::default()
+        }))
+    }
+
+    /// reads returns the number of reads issued so far.
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    /// writes returns the number of writes issued so far.
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+}
+
+enum Op {
+    Write(u64, Vec<u8>),
+    Truncate(u64),
+}
+
+impl Op {
+    fn apply(&self, data: &mut Vec<u8>) {
+        match self {
+            Op::Write(offset, buf) => {
+                let offset = *offset as usize;
+                if data.len() < offset + buf.len() {
+                    data.resize(offset + buf.len(), 0x00);
+                }
+                data[offset..offset + buf.len()].copy_from_slice(buf);
+            }
+            Op::Truncate(len) => data.resize(*len as usize, 0x00),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Disk {
+    /// The contents as seen by the process, including unsynced writes.
+    volatile: Vec<u8>,
+    /// The contents as of the last sync.
+    durable: Vec<u8>,
+    /// The operations issued since the last sync.
+    pending: Vec<Op>,
+    /// Whether the last pending operation is the write which crashed.
+    crashed_here: bool,
+}
+
+/// FaultyStorage is an in-memory Storage which keeps track of synced and unsynced writes,
+/// and can be crashed at an arbitrary write. Clones share the same underlying disk,
+/// so that the disk can still be inspected once the tree owning the storage crashed.
+#[derive(Clone)]
+pub struct FaultyStorage {
+    crash: Rc<RefCell<Crash>>,
+    disk: Rc<RefCell<Disk>>,
+}
+
+impl FaultyStorage {
+    pub fn new(crash: Rc<RefCell<Crash>>, contents: Vec<u8>) -> FaultyStorage {
+        FaultyStorage {
+            crash,
+            disk: Rc::new(RefCell::new(Disk {
+                volatile: contents.clone(),
+                durable: contents,
+                ..Disk::default()
+            })),
+        }
+    }
+
+    /// crash_image returns what would be found on disk after the crash,
+    /// deciding the fate of unsynced writes according to the given mode.
+    pub fn crash_image(&self, mode: FaultMode, rng: &mut Rng) -> Vec<u8> {
+        let disk = self.disk.borrow();
+        let mut image = disk.durable.clone();
+        match mode {
+            FaultMode::Drop => {
+                for op in disk.pending.iter() {
+                    if rng.below(2) == 0 {
+                        op.apply(&mut image);
+                    }
+                }
+            }
+            FaultMode::Reorder => {
+                let mut order: Vec<usize> = (0..disk.pending.len()).collect();
+                for i in (1..order.len()).rev() {
+                    order.swap(i, rng.below(i + 1));
+                }
+                let persisted = rng.below(order.len() + 1);
+                for idx in order.into_iter().take(persisted) {
+                    disk.pending[idx].apply(&mut image);
+                }
+            }
+            FaultMode::Tear => {
+                for (idx, op) in disk.pending.iter().enumerate() {
+                    match op {
+                        Op::Write(offset, buf)
+                            if disk.crashed_here && idx == disk.pending.len() - 1 =>
+                        {
+                            let torn = buf[..rng.below(buf.len())].to_vec();
+                            Op::Write(*offset, torn).apply(&mut image);
+                        }
+                        _ => op.apply(&mut image),
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    fn check_crashed(&self) -> Result<(), Error> {
+        match self.crash.borrow().crashed {
+            true => Err(Error::UnexpectedError),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        self.check_crashed()?;
+        self.crash.borrow_mut().reads += 1;
+        let disk = self.disk.borrow();
+        let offset = offset as usize;
+        if disk.volatile.len() < offset + buf.len() {
+            return Err(Error::UnexpectedError);
+        }
+        buf.copy_from_slice(&disk.volatile[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        self.check_crashed()?;
+        let mut crash = self.crash.borrow_mut();
+        let mut disk = self.disk.borrow_mut();
+        let op = Op::Write(offset, buf.to_vec());
+        if crash.crash_at == Some(crash.writes) {
+            // The crashing write may or may not have reached the disk.
+            crash.crashed = true;
+            disk.pending.push(op);
+            disk.crashed_here = true;
+            return Err(Error::UnexpectedError);
+        }
+        crash.writes += 1;
+        op.apply(&mut disk.volatile);
+        disk.pending.push(op);
+        Ok(())
+    }
+
+    fn len(&mut self) -> Result<u64, Error> {
+        self.check_crashed()?;
+        Ok(self.disk.borrow().volatile.len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.check_crashed()?;
+        let disk = &mut *self.disk.borrow_mut();
+        for op in disk.pending.drain(..) {
+            op.apply(&mut disk.durable);
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.check_crashed()?;
+        let mut disk = self.disk.borrow_mut();
+        let op = Op::Truncate(len);
+        op.apply(&mut disk.volatile);
+        disk.pending.push(op);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::{BTree, BTreeBuilder};
+    use crate::error::Error;
+    use crate::fault_injection::{Crash, FaultMode, FaultyStorage, Rng};
+    use crate::node_type::{Key, KeyValuePair};
+    use std::collections::btree_map::Entry;
+    use std::collections::BTreeMap;
+
+    type State = BTreeMap<[u8; 16], String>;
+
+    enum Action {
+        Insert([u8; 16], String),
+        Delete([u8; 16]),
+    }
+
+    /// Workload is a random sequence of inserts and deletes over a fixed universe of keys,
+    /// alongside the expected content of the tree after each committed action.
+    struct Workload {
+        universe: Vec<[u8; 16]>,
+        actions: Vec<Action>,
+        states: Vec<State>,
+    }
+
+    impl Workload {
+        fn new(rng: &mut Rng, num_keys: usize, num_actions: usize) -> Workload {
+            let universe: Vec<[u8; 16]> = (0..num_keys)
+                .map(|_| {
+                    let mut key = [0x00; 16];
+                    key[..8].copy_from_slice(&rng.next_u64().to_be_bytes());
+                    key[8..].copy_from_slice(&rng.next_u64().to_be_bytes());
+                    key
+                })
+                .collect();
+            let mut actions = Vec::new();
+            let mut states = vec![State::new()];
+            for i in 0..num_actions {
+                let mut state = states[i].clone();
+                let key = universe[rng.below(num_keys)];
+                match state.entry(key) {
+                    Entry::Occupied(entry) => {
+                        entry.remove();
+                        actions.push(Action::Delete(key));
+                    }
+                    Entry::Vacant(entry) => {
+                        let value = format!("v{}", i);
+                        entry.insert(value.clone());
+                        actions.push(Action::Insert(key, value));
+                    }
+                }
+                states.push(state);
+            }
+            Workload {
+                universe,
+                actions,
+                states,
+            }
+        }
+    }
+
+    fn open(
+        db: &FaultyStorage,
+        wal: &FaultyStorage,
+        page_table: bool,
+    ) -> Result<BTree<FaultyStorage>, Error> {
+        BTreeBuilder::new()
+            .b_parameter(2)
+            .page_table(page_table)
+            .build_with_storage(db.clone(), wal.clone())
+    }
+
+    /// run opens a new tree and applies the workload to it until it crashes,
+    /// returning the number of actions which completed.
+    fn run(
+        workload: &Workload,
+        db: &FaultyStorage,
+        wal: &FaultyStorage,
+        page_table: bool,
+    ) -> usize {
+        let mut btree = match open(db, wal, page_table) {
+            Ok(btree) => btree,
+            Err(_) => return 0,
+        };
+        for (i, action) in workload.actions.iter().enumerate() {
+            let res = match action {
+                Action::Insert(key, value) => btree.insert(KeyValuePair::new(*key, value.clone())),
+                Action::Delete(key) => btree.delete(Key(*key)),
+            };
+            if res.is_err() {
+                return i;
+            }
+        }
+        workload.actions.len()
+    }
+
+    /// read returns the content of the tree restricted to the workload's universe of keys.
+    fn read(btree: &mut BTree<FaultyStorage>, workload: &Workload) -> Result<State, Error> {
+        let mut state = State::new();
+        for key in workload.universe.iter() {
+            match btree.search(key) {
+                Ok(kv) => {
+                    state.insert(kv.key, kv.value);
+                }
+                Err(Error::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(state)
+    }
+
+    fn crash_at_every_write(seed: u64, mode: FaultMode, page_table: bool) -> Result<(), Error> {
+        let mut rng = Rng::new(seed);
+        let workload = Workload::new(&mut rng, 24, 60);
+
+        // A dry run counts the writes and sanity checks the workload itself.
+        let crash = Crash::new(None);
+        let db = FaultyStorage::new(crash.clone(), vec![]);
+        let wal = FaultyStorage::new(crash.clone(), vec![]);
+        assert_eq!(
+            run(&workload, &db, &wal, page_table),
+            workload.actions.len()
+        );
+        let total_writes = crash.borrow().writes();
+        let mut btree = open(&db, &wal, page_table)?;
+        assert_eq!(
+            &read(&mut btree, &workload)?,
+            workload.states.last().unwrap()
+        );
+
+        for crash_at in 0..total_writes {
+            let crash = Crash::new(Some(crash_at));
+            let db = FaultyStorage::new(crash.clone(), vec![]);
+            let wal = FaultyStorage::new(crash.clone(), vec![]);
+            let completed = run(&workload, &db, &wal, page_table);
+            assert!(completed < workload.actions.len());
+
+            // Reboot from whatever made it to disk.
+            let rebooted = Crash::new(None);
+            let db = FaultyStorage::new(rebooted.clone(), db.crash_image(mode, &mut rng));
+            let wal = FaultyStorage::new(rebooted.clone(), wal.crash_image(mode, &mut rng));
+            let mut btree = open(&db, &wal, page_table)?;
+            let recovered = read(&mut btree, &workload)?;
+            // Every completed action is durable, the action in flight may or may not be.
+            assert!(
+                recovered == workload.states[completed]
+                    || recovered == workload.states[completed + 1],
+                "seed {} mode {:?}: crash at write {} after {} actions recovered an uncommitted state",
+                seed,
+                mode,
+                crash_at,
+                completed,
+            );
+
+            // The recovered tree keeps working.
+            let key = [0xff; 16];
+            btree.insert(KeyValuePair::new(key, "recovered".to_string()))?;
+            assert_eq!(btree.search(&key)?.value, "recovered");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn recovers_from_dropped_writes() -> Result<(), Error> {
+        crash_at_every_write(0x5eed, FaultMode::Drop, true)
+    }
+
+    #[test]
+    fn recovers_from_reordered_writes() -> Result<(), Error> {
+        crash_at_every_write(0xc0ffee, FaultMode::Reorder, true)
+    }
+
+    #[test]
+    fn recovers_from_torn_writes() -> Result<(), Error> {
+        crash_at_every_write(0xbadc0de, FaultMode::Tear, true)
+    }
+
+    #[test]
+    fn recovers_without_page_table() -> Result<(), Error> {
+        crash_at_every_write(0xfeed, FaultMode::Reorder, false)
+    }
+}
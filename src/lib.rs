@@ -1,8 +1,14 @@
 pub mod btree;
+pub mod change;
+#[cfg(feature = "encryption")]
+mod cipher;
 pub mod error;
+#[cfg(test)]
+mod fault_injection;
 pub mod node;
 pub mod node_type;
 pub mod page;
 mod page_layout;
 mod pager;
+pub mod storage;
 mod wal;
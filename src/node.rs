@@ -1,10 +1,12 @@
 use crate::error::Error;
-use crate::node_type::{Key, KeyValuePair, NodeType, Offset};
-use crate::page::Page;
+use crate::node_type::{Key, KeyValuePair, NodeType, PageId};
+use crate::page::{Page, Value};
 use crate::page_layout::{
-    FromByte, INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET, IS_ROOT_OFFSET,
-    KEY_SIZE, LEAF_NODE_HEADER_SIZE, LEAF_NODE_NUM_PAIRS_OFFSET, NODE_TYPE_OFFSET,
-    PARENT_POINTER_OFFSET, PTR_SIZE, VALUE_SIZE,
+    FromByte, EXPIRES_SIZE, EXPIRY_SIZE, INTERNAL_NODE_COUNTED_OFFSET, INTERNAL_NODE_HEADER_SIZE,
+    INTERNAL_NODE_NUM_CHILDREN_OFFSET, INTERNAL_NODE_PREFIX_LEN_OFFSET, IS_ROOT_OFFSET,
+    KEY_LEN_SIZE, KEY_SIZE, LEAF_NODE_EXPIRING_OFFSET, LEAF_NODE_HEADER_SIZE,
+    LEAF_NODE_NEXT_OFFSET, LEAF_NODE_NUM_PAIRS_OFFSET, LEAF_NODE_PREFIX_LEN_OFFSET,
+    LEAF_NODE_PREV_OFFSET, NODE_TYPE_OFFSET, PTR_SIZE, VALUE_SIZE,
 };
 use std::convert::TryFrom;
 use std::str;
@@ -14,53 +16,63 @@ use std::str;
 pub struct Node {
     pub node_type: NodeType,
     pub is_root: bool,
-    pub parent_offset: Option<Offset>,
+    /// The previous and next leaves in key order, internal nodes have no sibling links.
+    pub prev: Option<PageId>,
+    pub next: Option<PageId>,
 }
 
 // Node represents a node in the B-Tree.
 impl Node {
-    pub fn new(node_type: NodeType, is_root: bool, parent_offset: Option<Offset>) -> Node {
+    pub fn new(node_type: NodeType, is_root: bool) -> Node {
         Node {
             node_type,
             is_root,
-            parent_offset,
+            prev: None,
+            next: None,
+        }
+    }
+
+    /// count returns the number of pairs held under the node, unless the node is an internal
+    /// node which does not keep subtree counts.
+    pub fn count(&self) -> Option<usize> {
+        match &self.node_type {
+            NodeType::Leaf(pairs) => Some(pairs.len()),
+            NodeType::Internal(_, _, counts) if !counts.is_empty() => Some(counts.iter().sum()),
+            _ => None,
         }
     }
 
     /// split creates a sibling node from a given node by splitting the node in two around a median.
     /// split will split the child at b leaving the [0, b-1] keys
     /// while moving the set of [b, 2b-1] keys to the sibling.
+    /// The median of a split leaf is the shortest key separating its two halves, see `separator`.
+    /// Linking a split leaf and its sibling is left to the caller, which knows their ids.
     pub fn split(&mut self, b: usize) -> Result<(Key, Node), Error> {
         match self.node_type {
-            NodeType::Internal(ref mut children, ref mut keys) => {
+            NodeType::Internal(ref mut children, ref mut keys, ref mut counts) => {
                 // Populate siblings keys.
                 let mut sibling_keys = keys.split_off(b - 1);
                 // Pop median key - to be added to the parent..
                 let median_key = sibling_keys.remove(0);
-                // Populate siblings children.
+                // Populate siblings children, alongside their counts if any.
                 let sibling_children = children.split_off(b);
-                Ok((
-                    median_key,
-                    Node::new(
-                        NodeType::Internal(sibling_children, sibling_keys),
-                        false,
-                        self.parent_offset.clone(),
-                    ),
-                ))
+                let sibling_counts = match counts.is_empty() {
+                    true => Vec::new(),
+                    false => counts.split_off(b),
+                };
+                let node_type = NodeType::Internal(sibling_children, sibling_keys, sibling_counts);
+                Ok((median_key, Node::new(node_type, false)))
             }
             NodeType::Leaf(ref mut pairs) => {
                 // Populate siblings pairs.
                 let sibling_pairs = pairs.split_off(b);
-                // Pop median key.
-                let median_pair = pairs.get(b - 1).ok_or(Error::UnexpectedError)?.clone();
+                // Only the shortest key separating the two halves is promoted.
+                let last = pairs.get(b - 1).ok_or(Error::UnexpectedError)?;
+                let first = sibling_pairs.first().ok_or(Error::UnexpectedError)?;
 
                 Ok((
-                    Key(median_pair.key),
-                    Node::new(
-                        NodeType::Leaf(sibling_pairs),
-                        false,
-                        self.parent_offset.clone(),
-                    ),
+                    separator(&last.key, &first.key),
+                    Node::new(NodeType::Leaf(sibling_pairs), false),
                 ))
             }
             NodeType::Unexpected => Err(Error::UnexpectedError),
@@ -73,58 +85,59 @@ impl Node {
 impl TryFrom<Page> for Node {
     type Error = Error;
     fn try_from(page: Page) -> Result<Node, Error> {
-        let raw = page.get_data();
+        let raw = page.decompress()?;
         let node_type = NodeType::from(raw[NODE_TYPE_OFFSET]);
         let is_root = raw[IS_ROOT_OFFSET].from_byte();
-        let parent_offset: Option<Offset>;
-        if is_root {
-            parent_offset = None;
-        } else {
-            parent_offset = Some(Offset(page.get_value_from_offset(PARENT_POINTER_OFFSET)?));
-        }
 
         match node_type {
-            NodeType::Internal(mut children, mut keys) => {
-                let num_children = page.get_value_from_offset(INTERNAL_NODE_NUM_CHILDREN_OFFSET)?;
+            NodeType::Internal(mut children, mut keys, mut counts) => {
+                let num_children = value_at(&raw, INTERNAL_NODE_NUM_CHILDREN_OFFSET)?;
+                let prefix_len = raw[INTERNAL_NODE_PREFIX_LEN_OFFSET] as usize;
+                let counted = raw[INTERNAL_NODE_COUNTED_OFFSET].from_byte();
                 let mut offset = INTERNAL_NODE_HEADER_SIZE;
                 for _i in 1..=num_children {
-                    let child_offset = page.get_value_from_offset(offset)?;
-                    children.push(Offset(child_offset));
+                    let child_id = value_at(&raw, offset)?;
+                    children.push(PageId(child_id));
                     offset += PTR_SIZE;
                 }
+                if counted {
+                    for _i in 1..=num_children {
+                        counts.push(value_at(&raw, offset)?);
+                        offset += PTR_SIZE;
+                    }
+                }
 
+                let prefix = bytes_at(&raw, offset, prefix_len)?;
+                offset += prefix_len;
                 // Number of keys is always one less than the number of children (i.e. branching factor)
                 for _i in 1..num_children {
-                    let key_raw = page.get_ptr_from_offset(offset, KEY_SIZE);
-                    let key = match <&[u8; 16]>::try_from(key_raw) {
-                        Ok(key) => key,
-                        Err(_) => return Err(Error::UTF8Error),
-                    };
-                    offset += KEY_SIZE;
-                    // Trim leading or trailing zeros.
-                    keys.push(Key(*key));
+                    // Keys are truncated, the bytes past their length are 0xFF.
+                    let len = bytes_at(&raw, offset, KEY_LEN_SIZE)?[0] as usize;
+                    offset += KEY_LEN_SIZE;
+                    let suffix_len = len.max(prefix_len) - prefix_len;
+                    let suffix = bytes_at(&raw, offset, suffix_len)?;
+                    offset += suffix_len;
+                    keys.push(Key(truncated_key(prefix, suffix)?));
                 }
                 Ok(Node::new(
-                    NodeType::Internal(children, keys),
+                    NodeType::Internal(children, keys, counts),
                     is_root,
-                    parent_offset,
                 ))
             }
 
             NodeType::Leaf(mut pairs) => {
-                let mut offset = LEAF_NODE_NUM_PAIRS_OFFSET;
-                let num_keys_val_pairs = page.get_value_from_offset(offset)?;
-                offset = LEAF_NODE_HEADER_SIZE;
+                let num_keys_val_pairs = value_at(&raw, LEAF_NODE_NUM_PAIRS_OFFSET)?;
+                let prefix_len = raw[LEAF_NODE_PREFIX_LEN_OFFSET] as usize;
+                let expiring = raw[LEAF_NODE_EXPIRING_OFFSET].from_byte();
+                let prefix = bytes_at(&raw, LEAF_NODE_HEADER_SIZE, prefix_len)?;
+                let mut offset = LEAF_NODE_HEADER_SIZE + prefix_len;
 
                 for _i in 0..num_keys_val_pairs {
-                    let key_raw = page.get_ptr_from_offset(offset, KEY_SIZE);
-                    let key = match <&[u8; 16]>::try_from(key_raw) {
-                        Ok(key) => key,
-                        Err(_) => return Err(Error::UTF8Error),
-                    };
-                    offset += KEY_SIZE;
+                    let suffix = bytes_at(&raw, offset, KEY_SIZE.saturating_sub(prefix_len))?;
+                    let key = key(prefix, suffix)?;
+                    offset += suffix.len();
 
-                    let value_raw = page.get_ptr_from_offset(offset, VALUE_SIZE);
+                    let value_raw = bytes_at(&raw, offset, VALUE_SIZE)?;
                     let value = match str::from_utf8(value_raw) {
                         Ok(val) => val,
                         Err(_) => return Err(Error::UTF8Error),
@@ -132,12 +145,20 @@ impl TryFrom<Page> for Node {
                     offset += VALUE_SIZE;
 
                     // Trim leading or trailing zeros.
-                    pairs.push(KeyValuePair::new(
-                        *key,
-                        value.trim_matches(char::from(0)).to_string(),
-                    ))
+                    let mut pair =
+                        KeyValuePair::new(key, value.trim_matches(char::from(0)).to_string());
+                    if expiring {
+                        let expires = bytes_at(&raw, offset, EXPIRES_SIZE)?[0].from_byte();
+                        let expires_at = value_at(&raw, offset + EXPIRES_SIZE)? as u64;
+                        pair.expires_at = Some(expires_at).filter(|_| expires);
+                        offset += EXPIRY_SIZE;
+                    }
+                    pairs.push(pair);
                 }
-                Ok(Node::new(NodeType::Leaf(pairs), is_root, parent_offset))
+                let mut node = Node::new(NodeType::Leaf(pairs), is_root);
+                node.prev = sibling(value_at(&raw, LEAF_NODE_PREV_OFFSET)?);
+                node.next = sibling(value_at(&raw, LEAF_NODE_NEXT_OFFSET)?);
+                Ok(node)
             }
 
             NodeType::Unexpected => Err(Error::UnexpectedError),
@@ -145,12 +166,69 @@ impl TryFrom<Page> for Node {
     }
 }
 
+/// bytes_at returns the bytes of a node at a given offset, failing rather than reading
+/// past the end of the node.
+fn bytes_at(raw: &[u8], offset: usize, size: usize) -> Result<&[u8], Error> {
+    raw.get(offset..offset + size).ok_or(Error::UnexpectedError)
+}
+
+/// value_at reads a value (as BigEndian) of a node at a given offset.
+fn value_at(raw: &[u8], offset: usize) -> Result<usize, Error> {
+    let Value(res) = Value::try_from(bytes_at(raw, offset, PTR_SIZE)?)?;
+    Ok(res)
+}
+
+/// separator returns the shortest key which is no less than the last key of a leaf
+/// and less than the first key of its next leaf: the prefix the two keys share, followed by
+/// the next byte of the last key. The remaining bytes are set to 0xFF, which internal nodes
+/// do not store.
+fn separator(last: &[u8; KEY_SIZE], first: &[u8; KEY_SIZE]) -> Key {
+    let len = last.iter().zip(first).take_while(|(a, b)| a == b).count() + 1;
+    if len > KEY_SIZE {
+        return Key(*last);
+    }
+    let mut key = [0xFF; KEY_SIZE];
+    key[..len].copy_from_slice(&last[..len]);
+    Key(key)
+}
+
+/// key puts a key back together from the prefix of its page and its own suffix.
+fn key(prefix: &[u8], suffix: &[u8]) -> Result<[u8; KEY_SIZE], Error> {
+    if prefix.len() + suffix.len() != KEY_SIZE {
+        return Err(Error::UnexpectedError);
+    }
+    let mut key = [0x00; KEY_SIZE];
+    key[..prefix.len()].copy_from_slice(prefix);
+    key[prefix.len()..].copy_from_slice(suffix);
+    Ok(key)
+}
+
+/// truncated_key puts a truncated key back together from the prefix of its page and its own
+/// suffix, padding it with 0xFF.
+fn truncated_key(prefix: &[u8], suffix: &[u8]) -> Result<[u8; KEY_SIZE], Error> {
+    let len = prefix.len() + suffix.len();
+    if len > KEY_SIZE {
+        return Err(Error::UnexpectedError);
+    }
+    let mut key = [0xFF; KEY_SIZE];
+    key[..prefix.len()].copy_from_slice(prefix);
+    key[prefix.len()..len].copy_from_slice(suffix);
+    Ok(key)
+}
+
+/// sibling decodes a sibling link, where zero stands for no sibling.
+fn sibling(id: usize) -> Option<PageId> {
+    match id {
+        0 => None,
+        id => Some(PageId(id)),
+    }
+}
+
 ////////////////////
 ///              ///
 ///  Unit Tests. ///
 ///              ///
 ////////////////////
-
 #[cfg(test)]
 mod tests {
     use crate::error::Error;
@@ -158,7 +236,7 @@ mod tests {
         Node, Page, INTERNAL_NODE_HEADER_SIZE, KEY_SIZE, LEAF_NODE_HEADER_SIZE, PTR_SIZE,
         VALUE_SIZE,
     };
-    use crate::node_type::{Key, NodeType};
+    use crate::node_type::{Key, NodeType, PageId};
     use crate::page_layout::PAGE_SIZE;
     use std::convert::TryFrom;
     use uuid::{uuid, Uuid};
@@ -170,12 +248,16 @@ mod tests {
         let mut page_data: [u8; DATA_LEN] = [
             0x01, // Is-Root byte.
             0x02, // Leaf Node type byte.
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Parent offset.
+            0x00, // Codec byte.
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Previous leaf id.
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, // Next leaf id.
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // Number of Key-Value pairs.
+            0x00, // Key prefix length.
+            0x00, // Expiring byte.
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Empty
             0x77, 0x6f, 0x72, 0x6c, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, // "world"
         ];
-        page_data[18..34].copy_from_slice(&ID.into_bytes());
+        page_data[29..45].copy_from_slice(&ID.into_bytes());
         let junk: [u8; PAGE_SIZE - DATA_LEN] = [0x00; PAGE_SIZE - DATA_LEN];
         let mut page = [0x00; PAGE_SIZE];
         for (to, from) in page.iter_mut().zip(page_data.iter().chain(junk.iter())) {
@@ -184,7 +266,9 @@ mod tests {
 
         let node = Node::try_from(Page::new(page))?;
 
-        assert_eq!(node.is_root, true);
+        assert!(node.is_root);
+        assert_eq!(node.prev, None);
+        assert_eq!(node.next, Some(PageId(7)));
         Ok(())
     }
 
@@ -193,20 +277,25 @@ mod tests {
         use crate::node_type::Key;
         const ID: Uuid = uuid!("0192f716-1f23-7a76-912f-34c661e13091");
         const SECOND_ID: Uuid = uuid!("0192f7c6-ce15-7c08-a9bc-35789cdf190e");
-        const DATA_LEN: usize = INTERNAL_NODE_HEADER_SIZE + 3 * PTR_SIZE + 2 * KEY_SIZE;
+        // Both keys share a three bytes prefix, which is stored once.
+        const DATA_LEN: usize = INTERNAL_NODE_HEADER_SIZE + 3 * PTR_SIZE + 3 + 2 + 13 + 3;
         let mut page_data: [u8; DATA_LEN] = [
             0x01, // Is-Root byte.
             0x01, // Internal Node type byte.
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Parent offset.
+            0x00, // Codec byte.
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, // Number of children.
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, // 4096  (2nd Page)
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, // 8192  (3rd Page)
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x00, // 12288 (4th Page)
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Empty
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Empty
+            0x03, // Key prefix length.
+            0x00, // Counted byte.
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, // 2nd Page
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, // 3rd Page
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, // 4th Page
+            0x01, 0x92, 0xf7, // Key prefix.
+            0x10, // Key length.
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Empty
+            0x06, // Truncated key length.
+            0xc6, 0xce, 0x15, // Truncated key suffix.
         ];
-        page_data[42..58].copy_from_slice(&ID.into_bytes());
-        page_data[58..74].copy_from_slice(&SECOND_ID.into_bytes());
+        page_data[41..54].copy_from_slice(&ID.into_bytes()[3..]);
         let junk: [u8; PAGE_SIZE - DATA_LEN] = [0x00; PAGE_SIZE - DATA_LEN];
 
         // Concatenate the two arrays; page_data and junk.
@@ -217,10 +306,10 @@ mod tests {
 
         let node = Node::try_from(Page::new(page))?;
 
-        if let NodeType::Internal(_, keys) = node.node_type {
+        if let NodeType::Internal(_, keys, _) = node.node_type {
             assert_eq!(keys.len(), 2);
 
-            let Key(first_key) = match keys.get(0) {
+            let Key(first_key) = match keys.first() {
                 Some(key) => key,
                 None => return Err(Error::UnexpectedError),
             };
@@ -230,7 +319,9 @@ mod tests {
                 Some(key) => key,
                 None => return Err(Error::UnexpectedError),
             };
-            assert_eq!(*second_key, SECOND_ID.into_bytes());
+            let mut truncated = [0xFF; KEY_SIZE];
+            truncated[..6].copy_from_slice(&SECOND_ID.into_bytes()[..6]);
+            assert_eq!(*second_key, truncated);
             return Ok(());
         }
 
@@ -252,21 +343,26 @@ mod tests {
                 KeyValuePair::new(THIRD_ID.into_bytes(), "grande".to_string()),
             ]),
             true,
-            None,
         );
 
         let (median, sibling) = node.split(2)?;
-        assert_eq!(median, Key(SECOND_ID.into_bytes()));
+        // The median is cut short right after the first byte telling the halves apart.
+        let mut separator = [0xFF; 16];
+        separator[..4].copy_from_slice(&SECOND_ID.into_bytes()[..4]);
+        assert_eq!(median, Key(separator));
+        assert!(median > Key(SECOND_ID.into_bytes()) && median < Key(THIRD_ID.into_bytes()));
         assert_eq!(
             node.node_type,
             NodeType::Leaf(vec![
                 KeyValuePair {
                     key: ID.into_bytes(),
-                    value: "bar".to_string()
+                    value: "bar".to_string(),
+                    expires_at: None
                 },
                 KeyValuePair {
                     key: SECOND_ID.into_bytes(),
-                    value: "james".to_string()
+                    value: "james".to_string(),
+                    expires_at: None
                 }
             ])
         );
@@ -284,28 +380,22 @@ mod tests {
     fn split_internal_works() -> Result<(), Error> {
         use crate::node::Node;
         use crate::node_type::NodeType;
-        use crate::node_type::{Key, Offset};
-        use crate::page_layout::PAGE_SIZE;
+        use crate::node_type::{Key, PageId};
 
         const ID: Uuid = uuid!("0192f716-1f23-7a76-912f-34c661e13091");
         const SECOND_ID: Uuid = uuid!("0192f7c6-ce15-7c08-a9bc-35789cdf190e");
         const THIRD_ID: Uuid = uuid!("0192f7c8-4d74-7b13-9de5-64dbff09b9ff");
         let mut node = Node::new(
             NodeType::Internal(
-                vec![
-                    Offset(PAGE_SIZE),
-                    Offset(PAGE_SIZE * 2),
-                    Offset(PAGE_SIZE * 3),
-                    Offset(PAGE_SIZE * 4),
-                ],
+                vec![PageId(2), PageId(3), PageId(4), PageId(5)],
                 vec![
                     Key(ID.into_bytes()),
                     Key(SECOND_ID.into_bytes()),
                     Key(THIRD_ID.into_bytes()),
                 ],
+                vec![1, 2, 3, 4],
             ),
             true,
-            None,
         );
 
         let (median, sibling) = node.split(2)?;
@@ -313,17 +403,20 @@ mod tests {
         assert_eq!(
             node.node_type,
             NodeType::Internal(
-                vec![Offset(PAGE_SIZE), Offset(PAGE_SIZE * 2)],
-                vec![Key(ID.into_bytes())]
+                vec![PageId(2), PageId(3)],
+                vec![Key(ID.into_bytes())],
+                vec![1, 2]
             )
         );
         assert_eq!(
             sibling.node_type,
             NodeType::Internal(
-                vec![Offset(PAGE_SIZE * 3), Offset(PAGE_SIZE * 4)],
-                vec![Key(THIRD_ID.into_bytes())]
+                vec![PageId(4), PageId(5)],
+                vec![Key(THIRD_ID.into_bytes())],
+                vec![3, 4]
             )
         );
+        assert_eq!(node.count(), Some(3));
         Ok(())
     }
 }
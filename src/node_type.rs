@@ -16,6 +16,21 @@ impl TryFrom<[u8; PTR_SIZE]> for Offset {
     }
 }
 
+/// PageId is the logical id of a page, which the pager maps to the page's current Offset.
+/// Nodes refer to each other by id, so that a node rewritten to a new offset
+/// keeps being referenced by its parent and siblings.
+#[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash, Debug)]
+pub struct PageId(pub usize);
+
+/// Converts an array of length len(usize) to a usize as a BigEndian integer.
+impl TryFrom<[u8; PTR_SIZE]> for PageId {
+    type Error = Error;
+
+    fn try_from(arr: [u8; PTR_SIZE]) -> Result<Self, Self::Error> {
+        Ok(PageId(usize::from_be_bytes(arr)))
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Debug)]
 pub struct Key(pub [u8; 16]);
 
@@ -23,6 +38,10 @@ pub struct Key(pub [u8; 16]);
 pub struct KeyValuePair {
     pub key: [u8; 16],
     pub value: String,
+    /// The time the pair expires at in milliseconds since the Unix epoch, like the timestamps
+    /// of UUIDv7 keys, if ever. Expired pairs are left out of searches and scans, but are
+    /// counted by `len`, `count_range`, `count_prefix`, `rank` and `select` until they are purged.
+    pub expires_at: Option<u64>,
 }
 
 impl Ord for KeyValuePair {
@@ -39,21 +58,43 @@ impl PartialOrd for KeyValuePair {
 
 impl PartialEq for KeyValuePair {
     fn eq(&self, other: &Self) -> bool {
-        self.key == other.key && self.value == other.value
+        self.key == other.key && self.value == other.value && self.expires_at == other.expires_at
     }
 }
 
 impl KeyValuePair {
     pub fn new(key: [u8; 16], value: String) -> KeyValuePair {
-        KeyValuePair { key, value }
+        KeyValuePair {
+            key,
+            value,
+            expires_at: None,
+        }
+    }
+
+    /// with_expiry returns a pair which expires at a given time,
+    /// in milliseconds since the Unix epoch. A pair expiring at zero has always expired.
+    pub fn with_expiry(key: [u8; 16], value: String, expires_at: u64) -> KeyValuePair {
+        KeyValuePair {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        }
+    }
+
+    /// is_expired tells whether the pair has expired at a given time,
+    /// in milliseconds since the Unix epoch.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
     }
 }
 
 // NodeType Represents different node types in the BTree.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum NodeType {
-    /// Internal nodes contain a vector of pointers to their children and a vector of keys.
-    Internal(Vec<Offset>, Vec<Key>),
+    /// Internal nodes contain a vector of the ids of their children and a vector of keys.
+    /// Trees keeping subtree counts also store the number of pairs held under every child,
+    /// the vector of counts is left empty otherwise.
+    Internal(Vec<PageId>, Vec<Key>, Vec<usize>),
 
     /// Leaf nodes contain a vector of Keys and values.
     Leaf(Vec<KeyValuePair>),
@@ -65,7 +106,7 @@ pub enum NodeType {
 impl From<u8> for NodeType {
     fn from(orig: u8) -> NodeType {
         match orig {
-            0x01 => NodeType::Internal(Vec::<Offset>::new(), Vec::<Key>::new()),
+            0x01 => NodeType::Internal(Vec::<PageId>::new(), Vec::<Key>::new(), Vec::new()),
             0x02 => NodeType::Leaf(Vec::<KeyValuePair>::new()),
             _ => NodeType::Unexpected,
         }
@@ -76,7 +117,7 @@ impl From<u8> for NodeType {
 impl From<&NodeType> for u8 {
     fn from(orig: &NodeType) -> u8 {
         match orig {
-            NodeType::Internal(_, _) => 0x01,
+            NodeType::Internal(_, _, _) => 0x01,
             NodeType::Leaf(_) => 0x02,
             NodeType::Unexpected => 0x03,
         }
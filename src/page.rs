@@ -1,11 +1,9 @@
 use crate::error::Error;
 use crate::node::Node;
-use crate::node_type::{Key, NodeType, Offset};
+use crate::node_type::{Key, NodeType, PageId};
 use crate::page_layout::{
-    ToByte, INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET,
-    INTERNAL_NODE_NUM_CHILDREN_SIZE, IS_ROOT_OFFSET, KEY_SIZE, LEAF_NODE_HEADER_SIZE,
-    LEAF_NODE_NUM_PAIRS_OFFSET, LEAF_NODE_NUM_PAIRS_SIZE, NODE_TYPE_OFFSET, PAGE_SIZE,
-    PARENT_POINTER_OFFSET, PARENT_POINTER_SIZE, PTR_SIZE, VALUE_SIZE,
+    ToByte, CODEC_LZ4, CODEC_NONE, CODEC_OFFSET, COMMON_NODE_HEADER_SIZE, IS_ROOT_OFFSET, KEY_SIZE,
+    NODE_TYPE_OFFSET, PAGE_SIZE, PTR_SIZE, VALUE_SIZE,
 };
 use std::convert::TryFrom;
 
@@ -88,74 +86,180 @@ impl Page {
 
 /// Implement TryFrom<Box<Node>> for Page allowing for easier
 /// serialization of data from a Node to an on-disk formatted page.
+/// Fails with `Error::PageOverflowError` if the node does not fit in a page.
 impl TryFrom<&Node> for Page {
     type Error = Error;
     fn try_from(node: &Node) -> Result<Page, Error> {
+        let raw = to_bytes(node)?;
+        if raw.len() > PAGE_SIZE {
+            return Err(Error::PageOverflowError);
+        }
         let mut data: [u8; PAGE_SIZE] = [0x00; PAGE_SIZE];
-        // is_root byte
-        data[IS_ROOT_OFFSET] = node.is_root.to_byte();
-
-        // node_type byte
-        data[NODE_TYPE_OFFSET] = u8::from(&node.node_type);
-
-        // parent offest
-        if !node.is_root {
-            match node.parent_offset {
-                Some(Offset(parent_offset)) => data
-                    [PARENT_POINTER_OFFSET..PARENT_POINTER_OFFSET + PARENT_POINTER_SIZE]
-                    .clone_from_slice(&parent_offset.to_be_bytes()),
-                // Expected an offset of an inner / leaf node.
-                None => return Err(Error::UnexpectedError),
-            };
+        data[..raw.len()].clone_from_slice(&raw);
+        Ok(Page::new(data))
+    }
+}
+
+impl Page {
+    /// encoded_len returns the number of bytes a node takes up once serialized,
+    /// which tells whether it fits in a page.
+    pub fn encoded_len(node: &Node) -> Result<usize, Error> {
+        Ok(to_bytes(node)?.len())
+    }
+
+    /// decompress returns the serialized node held by the page, decompressing it
+    /// if the page was compressed. Fails if the page was compressed with a codec
+    /// which this build does not support.
+    pub fn decompress(&self) -> Result<Vec<u8>, Error> {
+        match self.data[CODEC_OFFSET] {
+            CODEC_NONE => Ok(self.data.to_vec()),
+            CODEC_LZ4 => decompress_lz4(&self.data[..]),
+            _ => Err(Error::UnexpectedError),
         }
+    }
+}
 
-        match &node.node_type {
-            NodeType::Internal(child_offsets, keys) => {
-                data[INTERNAL_NODE_NUM_CHILDREN_OFFSET
-                    ..INTERNAL_NODE_NUM_CHILDREN_OFFSET + INTERNAL_NODE_NUM_CHILDREN_SIZE]
-                    .clone_from_slice(&child_offsets.len().to_be_bytes());
-
-                let mut page_offset = INTERNAL_NODE_HEADER_SIZE;
-                for Offset(child_offset) in child_offsets {
-                    data[page_offset..page_offset + PTR_SIZE]
-                        .clone_from_slice(&child_offset.to_be_bytes());
-                    page_offset += PTR_SIZE;
-                }
+/// to_bytes serializes a node, compressing it if it would not fit in a page otherwise
+/// as there is nothing to gain from compressing nodes which do.
+fn to_bytes(node: &Node) -> Result<Vec<u8>, Error> {
+    let raw = encode(node)?;
+    if raw.len() <= PAGE_SIZE {
+        return Ok(raw);
+    }
+    compress_lz4(raw)
+}
 
-                for Key(key) in keys {
-                    data[page_offset..page_offset + KEY_SIZE].clone_from_slice(key);
-                    page_offset += KEY_SIZE
-                }
+/// compress_lz4 compresses a serialized node past its common header,
+/// which is kept as is to record the codec.
+#[cfg(feature = "compression")]
+fn compress_lz4(raw: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let mut data = raw[..COMMON_NODE_HEADER_SIZE].to_vec();
+    data[CODEC_OFFSET] = CODEC_LZ4;
+    let body = lz4_flex::compress_prepend_size(&raw[COMMON_NODE_HEADER_SIZE..]);
+    data.extend_from_slice(&body.len().to_be_bytes());
+    data.extend(body);
+    Ok(data)
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress_lz4(raw: Vec<u8>) -> Result<Vec<u8>, Error> {
+    Ok(raw)
+}
+
+/// decompress_lz4 puts a serialized node back together from its compressed page.
+#[cfg(feature = "compression")]
+fn decompress_lz4(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut raw = data[..COMMON_NODE_HEADER_SIZE].to_vec();
+    raw[CODEC_OFFSET] = CODEC_NONE;
+    let offset = COMMON_NODE_HEADER_SIZE + PTR_SIZE;
+    let Value(len) = Value::try_from(&data[COMMON_NODE_HEADER_SIZE..offset])?;
+    let compressed = data
+        .get(offset..offset + len)
+        .ok_or(Error::UnexpectedError)?;
+    let body =
+        lz4_flex::decompress_size_prepended(compressed).map_err(|_| Error::UnexpectedError)?;
+    raw.extend(body);
+    Ok(raw)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_lz4(_data: &[u8]) -> Result<Vec<u8>, Error> {
+    // The page was written by a build with the compression feature.
+    Err(Error::UnexpectedError)
+}
+
+/// encode serializes a node in the on-disk page format, without padding it to a full page.
+fn encode(node: &Node) -> Result<Vec<u8>, Error> {
+    let mut data = vec![0x00; COMMON_NODE_HEADER_SIZE];
+    // is_root byte
+    data[IS_ROOT_OFFSET] = node.is_root.to_byte();
+
+    // node_type byte
+    data[NODE_TYPE_OFFSET] = u8::from(&node.node_type);
+
+    match &node.node_type {
+        NodeType::Internal(children, keys, counts) => {
+            data.extend_from_slice(&children.len().to_be_bytes());
+            let prefix_len = common_prefix_len(keys.iter().map(|Key(key)| key));
+            data.push(prefix_len as u8);
+            data.push((!counts.is_empty()).to_byte());
+
+            for PageId(child) in children {
+                data.extend_from_slice(&child.to_be_bytes());
             }
-            NodeType::Leaf(kv_pairs) => {
-                // num of pairs
-                data[LEAF_NODE_NUM_PAIRS_OFFSET
-                    ..LEAF_NODE_NUM_PAIRS_OFFSET + LEAF_NODE_NUM_PAIRS_SIZE]
-                    .clone_from_slice(&kv_pairs.len().to_be_bytes());
-
-                let mut page_offset = LEAF_NODE_HEADER_SIZE;
-                for pair in kv_pairs {
-                    data[page_offset..page_offset + KEY_SIZE].clone_from_slice(&pair.key);
-                    page_offset += KEY_SIZE;
-
-                    let value_bytes = pair.value.as_bytes();
-                    let mut raw_value: [u8; VALUE_SIZE] = [0x00; VALUE_SIZE];
-                    if value_bytes.len() > VALUE_SIZE {
-                        return Err(Error::ValueOverflowError);
-                    } else {
-                        for (i, byte) in value_bytes.iter().enumerate() {
-                            raw_value[i] = *byte;
-                        }
+            for count in counts {
+                data.extend_from_slice(&count.to_be_bytes());
+            }
+
+            // The prefix is stored once, followed by what is left of every key.
+            if let Some(Key(key)) = keys.first() {
+                data.extend_from_slice(&key[..prefix_len]);
+            }
+            for Key(key) in keys {
+                // Trailing 0xFF bytes are left out, see `Node::split`.
+                let len = KEY_SIZE - key.iter().rev().take_while(|byte| **byte == 0xFF).count();
+                data.push(len as u8);
+                data.extend_from_slice(&key[prefix_len..len.max(prefix_len)]);
+            }
+        }
+        NodeType::Leaf(kv_pairs) => {
+            // sibling links, zero stands for no sibling.
+            let PageId(prev) = node.prev.unwrap_or(PageId(0));
+            data.extend_from_slice(&prev.to_be_bytes());
+            let PageId(next) = node.next.unwrap_or(PageId(0));
+            data.extend_from_slice(&next.to_be_bytes());
+
+            // num of pairs
+            data.extend_from_slice(&kv_pairs.len().to_be_bytes());
+            let prefix_len = common_prefix_len(kv_pairs.iter().map(|pair| &pair.key));
+            data.push(prefix_len as u8);
+            // Expiry times are only stored in leaves holding pairs which expire.
+            let expiring = kv_pairs.iter().any(|pair| pair.expires_at.is_some());
+            data.push(expiring.to_byte());
+
+            // The prefix is stored once, followed by what is left of every key.
+            if let Some(pair) = kv_pairs.first() {
+                data.extend_from_slice(&pair.key[..prefix_len]);
+            }
+            for pair in kv_pairs {
+                data.extend_from_slice(&pair.key[prefix_len..]);
+
+                let value_bytes = pair.value.as_bytes();
+                let mut raw_value: [u8; VALUE_SIZE] = [0x00; VALUE_SIZE];
+                if value_bytes.len() > VALUE_SIZE {
+                    return Err(Error::ValueOverflowError);
+                } else {
+                    for (i, byte) in value_bytes.iter().enumerate() {
+                        raw_value[i] = *byte;
                     }
-                    data[page_offset..page_offset + VALUE_SIZE].clone_from_slice(&raw_value);
-                    page_offset += VALUE_SIZE;
+                }
+                data.extend_from_slice(&raw_value);
+                if expiring {
+                    data.push(pair.expires_at.is_some().to_byte());
+                    data.extend_from_slice(&pair.expires_at.unwrap_or(0).to_be_bytes());
                 }
             }
-            NodeType::Unexpected => return Err(Error::UnexpectedError),
         }
-
-        Ok(Page::new(data))
+        NodeType::Unexpected => return Err(Error::UnexpectedError),
     }
+
+    Ok(data)
+}
+
+/// common_prefix_len returns the length of the prefix shared by all of the given keys,
+/// which is the whole key if there is a single one.
+fn common_prefix_len<'a>(mut keys: impl Iterator<Item = &'a [u8; KEY_SIZE]>) -> usize {
+    let first = match keys.next() {
+        Some(first) => first,
+        None => return 0,
+    };
+    keys.fold(KEY_SIZE, |len, key| {
+        first[..len]
+            .iter()
+            .zip(key)
+            .take_while(|(a, b)| a == b)
+            .count()
+    })
 }
 
 /// Attempts to convert a slice to an array of a fixed size (PTR_SIZE),
@@ -185,22 +289,30 @@ mod tests {
     #[test]
     fn node_to_page_works_for_leaf_node() -> Result<(), Error> {
         use crate::node::Node;
-        use crate::node_type::{KeyValuePair, NodeType};
+        use crate::node_type::{KeyValuePair, NodeType, PageId};
         use crate::page::Page;
         use std::convert::TryFrom;
 
         const ID: Uuid = uuid!("0192f716-1f23-7a76-912f-34c661e13091");
         const SECOND_ID: Uuid = uuid!("0192f7c6-ce15-7c08-a9bc-35789cdf190e");
         const THIRD_ID: Uuid = uuid!("0192f7c8-4d74-7b13-9de5-64dbff09b9ff");
-        let some_leaf = Node::new(
+        const FOURTH_ID: Uuid = uuid!("0192f7c9-1a02-7c55-8e1b-2f6a0d6b4c31");
+        let mut some_leaf = Node::new(
             NodeType::Leaf(vec![
                 KeyValuePair::new(ID.into_bytes(), "bar".to_string()),
-                KeyValuePair::new(SECOND_ID.into_bytes(), "james".to_string()),
+                KeyValuePair::with_expiry(
+                    SECOND_ID.into_bytes(),
+                    "james".to_string(),
+                    1730000000000,
+                ),
                 KeyValuePair::new(THIRD_ID.into_bytes(), "grande".to_string()),
+                // A pair which expires at the very start of the epoch is told apart
+                // from the pairs which do not expire.
+                KeyValuePair::with_expiry(FOURTH_ID.into_bytes(), "zero".to_string(), 0),
             ]),
             true,
-            None,
         );
+        some_leaf.next = Some(PageId(3));
 
         // Serialize data.
         let page = Page::try_from(&some_leaf)?;
@@ -209,16 +321,16 @@ mod tests {
 
         assert_eq!(res.is_root, some_leaf.is_root);
         assert_eq!(res.node_type, some_leaf.node_type);
-        assert_eq!(res.parent_offset, some_leaf.parent_offset);
+        assert_eq!(res.prev, None);
+        assert_eq!(res.next, Some(PageId(3)));
         Ok(())
     }
 
     #[test]
     fn node_to_page_works_for_internal_node() -> Result<(), Error> {
         use crate::node::Node;
-        use crate::node_type::{Key, NodeType, Offset};
+        use crate::node_type::{Key, NodeType, PageId};
         use crate::page::Page;
-        use crate::page_layout::PAGE_SIZE;
         use std::convert::TryFrom;
 
         const ID: Uuid = uuid!("0192f716-1f23-7a76-912f-34c661e13091");
@@ -226,20 +338,15 @@ mod tests {
         const THIRD_ID: Uuid = uuid!("0192f7c8-4d74-7b13-9de5-64dbff09b9ff");
         let internal_node = Node::new(
             NodeType::Internal(
-                vec![
-                    Offset(PAGE_SIZE),
-                    Offset(PAGE_SIZE * 2),
-                    Offset(PAGE_SIZE * 3),
-                    Offset(PAGE_SIZE * 4),
-                ],
+                vec![PageId(2), PageId(3), PageId(4), PageId(5)],
                 vec![
                     Key(ID.into_bytes()),
                     Key(SECOND_ID.into_bytes()),
                     Key(THIRD_ID.into_bytes()),
                 ],
+                vec![1, 2, 3, 4],
             ),
             true,
-            None,
         );
 
         // Serialize data.
@@ -249,7 +356,109 @@ mod tests {
 
         assert_eq!(res.is_root, internal_node.is_root);
         assert_eq!(res.node_type, internal_node.node_type);
-        assert_eq!(res.parent_offset, internal_node.parent_offset);
+        Ok(())
+    }
+
+    #[test]
+    fn shared_key_prefixes_are_stored_once() -> Result<(), Error> {
+        use crate::node::Node;
+        use crate::node_type::{KeyValuePair, NodeType};
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+        use std::convert::TryFrom;
+
+        const ID: Uuid = uuid!("0192f716-1f23-7a76-912f-34c661e13091");
+        // 200 pairs take up 5200 bytes uncompressed, more than fit in a page.
+        let leaf = |key: &dyn Fn(usize) -> [u8; 16]| {
+            let pairs = (0..200)
+                .map(|i| KeyValuePair::new(key(i), "value".to_string()))
+                .collect();
+            Node::new(NodeType::Leaf(pairs), false)
+        };
+        // Keys sharing their first eight bytes (such as the timestamp of UUIDv7s) fit.
+        let shared = leaf(&|i| {
+            let mut key = ID.into_bytes();
+            key[8..].copy_from_slice(&i.to_be_bytes());
+            key
+        });
+        assert!(Page::encoded_len(&shared)? <= PAGE_SIZE);
+        let res = Node::try_from(Page::try_from(&shared)?)?;
+        assert_eq!(res.node_type, shared.node_type);
+
+        // Keys sharing nothing do not, unless compressed.
+        #[cfg(not(feature = "compression"))]
+        let distinct = leaf(&|i| {
+            let mut key = ID.into_bytes();
+            key[..8].copy_from_slice(&i.to_be_bytes());
+            key[0] = i as u8;
+            key
+        });
+        #[cfg(not(feature = "compression"))]
+        assert!(matches!(
+            Page::try_from(&distinct),
+            Err(Error::PageOverflowError)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_keys_are_stored_short() -> Result<(), Error> {
+        use crate::node::Node;
+        use crate::node_type::{Key, NodeType, PageId};
+        use crate::page::Page;
+        use crate::page_layout::PAGE_SIZE;
+        use std::convert::TryFrom;
+
+        // 300 separators of three significant bytes, full keys would not fit in a page.
+        let children = (1..=300).map(PageId).collect();
+        let keys = (0..299u16)
+            .map(|i| {
+                let mut key = [0xFF; 16];
+                key[0] = 0x01;
+                key[1..3].copy_from_slice(&i.to_be_bytes());
+                Key(key)
+            })
+            .collect();
+        let internal_node = Node::new(NodeType::Internal(children, keys, Vec::new()), false);
+        assert!(Page::encoded_len(&internal_node)? <= PAGE_SIZE);
+
+        let res = Node::try_from(Page::try_from(&internal_node)?)?;
+        assert_eq!(res.node_type, internal_node.node_type);
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn large_nodes_are_compressed() -> Result<(), Error> {
+        use crate::node::Node;
+        use crate::node_type::{KeyValuePair, NodeType};
+        use crate::page::Page;
+        use crate::page_layout::{CODEC_LZ4, CODEC_NONE, CODEC_OFFSET};
+        use std::convert::TryFrom;
+
+        let leaf = |len: usize| {
+            let pairs = (0..len)
+                .map(|i| {
+                    let mut key = [0x00; 16];
+                    key[..8].copy_from_slice(&i.to_be_bytes());
+                    key[0] = i as u8;
+                    KeyValuePair::new(key, "aaaaaaaaaa".to_string())
+                })
+                .collect();
+            Node::new(NodeType::Leaf(pairs), false)
+        };
+
+        // A node which fits in a page is left as is.
+        let small = leaf(10);
+        let page = Page::try_from(&small)?;
+        assert_eq!(page.get_data()[CODEC_OFFSET], CODEC_NONE);
+        assert_eq!(Node::try_from(page)?.node_type, small.node_type);
+
+        // 300 pairs take up 7800 bytes uncompressed.
+        let large = leaf(300);
+        let page = Page::try_from(&large)?;
+        assert_eq!(page.get_data()[CODEC_OFFSET], CODEC_LZ4);
+        assert_eq!(Node::try_from(page)?.node_type, large.node_type);
         Ok(())
     }
 }
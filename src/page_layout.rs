@@ -7,30 +7,73 @@ pub const PAGE_SIZE: usize = 4096;
 
 pub const PTR_SIZE: usize = size_of::<usize>();
 
-/// Common Node header layout (Ten bytes in total)
+/// Common Node header layout (Three bytes in total)
+///
+/// | Is-Root - 1 byte | Node type - 1 byte | Codec - 1 byte |
+///
+/// Nodes keep no pointer to their parent, the parents of a node are tracked in memory
+/// on the way down from the root instead.
 pub const IS_ROOT_SIZE: usize = 1;
 pub const IS_ROOT_OFFSET: usize = 0;
 pub const NODE_TYPE_SIZE: usize = 1;
 pub const NODE_TYPE_OFFSET: usize = 1;
-pub const PARENT_POINTER_OFFSET: usize = 2;
-pub const PARENT_POINTER_SIZE: usize = PTR_SIZE;
-pub const COMMON_NODE_HEADER_SIZE: usize = NODE_TYPE_SIZE + IS_ROOT_SIZE + PARENT_POINTER_SIZE;
+pub const CODEC_SIZE: usize = 1;
+pub const CODEC_OFFSET: usize = 2;
+pub const COMMON_NODE_HEADER_SIZE: usize = NODE_TYPE_SIZE + IS_ROOT_SIZE + CODEC_SIZE;
+
+/// The codec tells how the rest of the page is stored. A node which does not fit in a page
+/// as is can be compressed (see the `compression` feature), in which case the rest of
+/// the page holds the length of the compressed bytes (8 bytes), the size of the node (4 bytes)
+/// and the LZ4 compressed bytes of the node.
+pub const CODEC_NONE: u8 = 0x00;
+pub const CODEC_LZ4: u8 = 0x01;
 
-/// Leaf node header layout (Eighteen bytes in total)
+/// Leaf node header layout (Twenty nine bytes in total)
 ///
-/// Space for keys and values: PAGE_SIZE - LEAF_NODE_HEADER_SIZE = 4096 - 18 = 4078 bytes.
-/// Which leaves 4076 / keys_limit = 20 (ten for key and 10 for value).
-pub const LEAF_NODE_NUM_PAIRS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
+/// | Previous leaf id - 8 bytes | Next leaf id - 8 bytes | Number of pairs - 8 bytes |
+/// | Key prefix length - 1 byte | Expiring - 1 byte |
+///
+/// A sibling id of zero stands for no sibling, as page ids start at one.
+/// The header is followed by the key prefix and then by the pairs, each made of
+/// the suffix of its key and its value, followed by its expiry if any pair of the leaf expires:
+/// whether the pair expires (1 byte) and the time it expires at, in milliseconds since the
+/// Unix epoch (8 bytes), which is zero for a pair which does not expire.
+pub const LEAF_NODE_PREV_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
+pub const LEAF_NODE_PREV_SIZE: usize = PTR_SIZE;
+pub const LEAF_NODE_NEXT_OFFSET: usize = LEAF_NODE_PREV_OFFSET + LEAF_NODE_PREV_SIZE;
+pub const LEAF_NODE_NEXT_SIZE: usize = PTR_SIZE;
+pub const LEAF_NODE_NUM_PAIRS_OFFSET: usize = LEAF_NODE_NEXT_OFFSET + LEAF_NODE_NEXT_SIZE;
 pub const LEAF_NODE_NUM_PAIRS_SIZE: usize = PTR_SIZE;
-pub const LEAF_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE + LEAF_NODE_NUM_PAIRS_SIZE;
+pub const LEAF_NODE_PREFIX_LEN_OFFSET: usize =
+    LEAF_NODE_NUM_PAIRS_OFFSET + LEAF_NODE_NUM_PAIRS_SIZE;
+pub const LEAF_NODE_EXPIRING_OFFSET: usize = LEAF_NODE_PREFIX_LEN_OFFSET + PREFIX_LEN_SIZE;
+pub const LEAF_NODE_EXPIRING_SIZE: usize = 1;
+pub const LEAF_NODE_HEADER_SIZE: usize = LEAF_NODE_EXPIRING_OFFSET + LEAF_NODE_EXPIRING_SIZE;
+pub const EXPIRES_SIZE: usize = 1;
+pub const EXPIRY_TIME_SIZE: usize = 8;
+pub const EXPIRY_SIZE: usize = EXPIRES_SIZE + EXPIRY_TIME_SIZE;
 
-/// Internal header layout (Eighteen bytes in total)
+/// Internal header layout (Thirteen bytes in total)
+///
+/// | Number of children - 8 bytes | Key prefix length - 1 byte | Counted - 1 byte |
 ///
-// Space for children and keys: PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE = 4096 - 18 = 4078 bytes.
+/// The header is followed by the children ids, the subtree counts of the children (8 bytes each)
+/// if the node is counted, the key prefix and then the key suffixes.
+/// Keys of internal nodes only serve to separate their children, and are often truncated:
+/// every key is preceded by its length, the bytes past which are 0xFF and are not stored.
 pub const INTERNAL_NODE_NUM_CHILDREN_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
 pub const INTERNAL_NODE_NUM_CHILDREN_SIZE: usize = PTR_SIZE;
+pub const INTERNAL_NODE_PREFIX_LEN_OFFSET: usize =
+    INTERNAL_NODE_NUM_CHILDREN_OFFSET + INTERNAL_NODE_NUM_CHILDREN_SIZE;
+pub const INTERNAL_NODE_COUNTED_OFFSET: usize = INTERNAL_NODE_PREFIX_LEN_OFFSET + PREFIX_LEN_SIZE;
+pub const INTERNAL_NODE_COUNTED_SIZE: usize = 1;
 pub const INTERNAL_NODE_HEADER_SIZE: usize =
-    COMMON_NODE_HEADER_SIZE + INTERNAL_NODE_NUM_CHILDREN_SIZE;
+    INTERNAL_NODE_COUNTED_OFFSET + INTERNAL_NODE_COUNTED_SIZE;
+
+/// Keys are prefix compressed: the prefix shared by all of the keys of a node is stored
+/// once per page, and only the remaining suffix of every key is stored.
+pub const PREFIX_LEN_SIZE: usize = 1;
+pub const KEY_LEN_SIZE: usize = 1;
 
 /// On a 64 bit machine the maximum space to keep all of the pointer
 /// is 200 * 8 = 1600 bytes.
@@ -51,6 +94,7 @@ pub const VALUE_SIZE: usize = 10;
 
 /// Wrappers for converting byte to bool and back.
 /// The convention used throughout the index file is: one is true; otherwise - false.
+#[allow(clippy::wrong_self_convention)]
 pub trait FromByte {
     fn from_byte(&self) -> bool;
 }
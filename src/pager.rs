@@ -1,50 +1,275 @@
+#[cfg(feature = "encryption")]
+use crate::cipher::{Cipher, ENCRYPTED_PAGE_SIZE, KEY_SIZE};
 use crate::error::Error;
-use crate::node_type::Offset;
+use crate::node_type::{Offset, PageId};
 use crate::page::Page;
 use crate::page_layout::PAGE_SIZE;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
+use crate::storage::Storage;
+use crate::wal::{PageWrite, WalRecord};
+use std::collections::{BTreeMap, HashMap};
 
-pub struct Pager {
-    file: File,
+/// Pager maps the logical ids of pages to their offsets in storage.
+///
+/// Pages are never overwritten once committed: writing a page appends a new copy of it,
+/// which only replaces the committed copy once the writes are committed, so that
+/// a crash or a failed write always leaves the last committed version of every page behind.
+///
+/// A pager without page table finds every page by its id instead, which is the number of the
+/// page in storage counting from one: a committed page is written anew under a new id,
+/// see `relocate`.
+pub struct Pager<S: Storage> {
+    storage: S,
     curser: usize,
+    /// The curser as of the last commit, past which pages were not committed yet.
+    committed: usize,
+    /// Whether pages are mapped to their offsets by a page table.
+    page_table: bool,
+    /// The offset of every committed page.
+    pages: HashMap<PageId, Offset>,
+    /// The offset of every page written since the last commit.
+    dirty: BTreeMap<PageId, Offset>,
+    /// The next id to hand out, ids start at one.
+    next_id: usize,
+    /// The cipher pages are encrypted with, if any.
+    #[cfg(feature = "encryption")]
+    cipher: Option<Cipher>,
 }
 
-impl Pager {
-    pub fn new(path: &Path) -> Result<Pager, Error> {
-        let fd = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
-
-        Ok(Pager {
-            file: fd,
+impl<S: Storage> Pager<S> {
+    pub fn new(storage: S) -> Result<Pager<S>, Error> {
+        let mut pager = Pager {
+            storage,
             curser: 0,
-        })
+            committed: 0,
+            page_table: true,
+            pages: HashMap::new(),
+            dirty: BTreeMap::new(),
+            next_id: 1,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        };
+        pager.seek_end()?;
+        Ok(pager)
     }
 
-    pub fn get_page(&mut self, offset: &Offset) -> Result<Page, Error> {
+    /// encrypted returns a pager which encrypts every page under a given key.
+    /// Encrypted pages take up more room on disk, see `ENCRYPTED_PAGE_SIZE`.
+    #[cfg(feature = "encryption")]
+    pub fn encrypted(storage: S, key: &[u8; KEY_SIZE]) -> Result<Pager<S>, Error> {
+        let mut pager = Pager::new(storage)?;
+        pager.cipher = Some(Cipher::new(key));
+        pager.seek_end()?;
+        Ok(pager)
+    }
+
+    /// sibling returns a pager over another storage, which encrypts pages as this one does,
+    /// e.g. to copy pages over to a backup of the tree.
+    pub fn sibling<T: Storage>(&self, storage: T) -> Result<Pager<T>, Error> {
+        #[allow(unused_mut)]
+        let mut pager = Pager::new(storage)?;
+        #[cfg(feature = "encryption")]
+        {
+            pager.cipher = self.cipher.clone();
+            pager.seek_end()?;
+        }
+        pager.page_table = self.page_table;
+        Ok(pager)
+    }
+
+    /// seek_end moves the curser past the pages in storage.
+    fn seek_end(&mut self) -> Result<(), Error> {
+        // New pages are appended past the existing ones, a partially written page is
+        // left behind as junk.
+        let len = self.storage.len()? as usize;
+        self.curser = len.div_ceil(self.page_size()) * self.page_size();
+        self.committed = self.curser;
+        Ok(())
+    }
+
+    /// has_page_table returns whether pages are mapped to their offsets by a page table.
+    pub fn has_page_table(&self) -> bool {
+        self.page_table
+    }
+
+    /// set_page_table sets whether pages are mapped to their offsets by a page table,
+    /// which is up to the tree the pages belong to.
+    pub fn set_page_table(&mut self, page_table: bool) {
+        self.page_table = page_table;
+    }
+
+    /// page_size returns the room a page takes up in storage.
+    fn page_size(&self) -> usize {
+        #[cfg(feature = "encryption")]
+        if self.cipher.is_some() {
+            return ENCRYPTED_PAGE_SIZE;
+        }
+        PAGE_SIZE
+    }
+
+    /// allocate hands out the id of a new page.
+    pub fn allocate(&mut self) -> PageId {
+        if !self.page_table {
+            // The page is appended past the existing ones.
+            let id = PageId(self.curser / self.page_size() + 1);
+            self.curser += self.page_size();
+            return id;
+        }
+        let id = PageId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    /// relocate returns the id to write a new copy of the page with a given id under.
+    /// A page mapped by the page table keeps its id, otherwise a page which was committed
+    /// is given a new id, which the nodes referring to it have to be updated with.
+    pub fn relocate(&mut self, id: &PageId) -> PageId {
+        match self.page_table || self.page_offset(id) >= self.committed {
+            true => *id,
+            false => self.allocate(),
+        }
+    }
+
+    /// page_offset returns the offset of the page with a given id in a pager without page table.
+    fn page_offset(&self, id: &PageId) -> usize {
+        id.0.saturating_sub(1) * self.page_size()
+    }
+
+    pub fn get_page(&mut self, id: &PageId) -> Result<Page, Error> {
+        let offset = match self.dirty.get(id) {
+            Some(offset) => offset.clone(),
+            None => self.offset(id).ok_or(Error::UnexpectedError)?,
+        };
+        self.read_page(id, &offset)
+    }
+
+    /// offset returns the offset of the committed copy of the page with a given id, if any.
+    pub fn offset(&self, id: &PageId) -> Option<Offset> {
+        if !self.page_table {
+            return Some(Offset(self.page_offset(id)));
+        }
+        self.pages.get(id).cloned()
+    }
+
+    /// read_page reads the copy of the page with a given id stored at a given offset,
+    /// e.g. a copy which was replaced by a later commit.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub fn read_page(&mut self, id: &PageId, offset: &Offset) -> Result<Page, Error> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            let mut data = vec![0x00; ENCRYPTED_PAGE_SIZE];
+            self.storage.read_at(&mut data, offset.0 as u64)?;
+            return Ok(Page::new(cipher.decrypt(&data, id.0, offset.0)?));
+        }
         let mut page: [u8; PAGE_SIZE] = [0x00; PAGE_SIZE];
-        self.file.seek(SeekFrom::Start(offset.0 as u64))?;
-        self.file.read_exact(&mut page)?;
+        self.storage.read_at(&mut page, offset.0 as u64)?;
         Ok(Page::new(page))
     }
 
-    pub fn write_page(&mut self, page: Page) -> Result<Offset, Error> {
-        self.file.seek(SeekFrom::Start(self.curser as u64))?;
-        self.file.write_all(&page.get_data())?;
-        let res = Offset(self.curser);
-        self.curser += PAGE_SIZE;
-        Ok(res)
+    /// write_page writes a new copy of the page with the given id.
+    /// A page written more than once before a commit is overwritten in place,
+    /// as nothing committed refers to its copy yet.
+    /// Without page table the page is written at the offset its id stands for, which must not
+    /// be the id of a committed page, see `relocate`.
+    pub fn write_page(&mut self, id: &PageId, page: Page) -> Result<(), Error> {
+        if let Some(Offset(offset)) = self.dirty.get(id) {
+            let offset = *offset;
+            let data = self.seal(&page, id, offset)?;
+            return self.storage.write_at(&data, offset as u64);
+        }
+        if !self.page_table {
+            let offset = self.page_offset(id);
+            let data = self.seal(&page, id, offset)?;
+            self.storage.write_at(&data, offset as u64)?;
+            self.dirty.insert(*id, Offset(offset));
+            return Ok(());
+        }
+        let data = self.seal(&page, id, self.curser)?;
+        self.storage.write_at(&data, self.curser as u64)?;
+        self.dirty.insert(*id, Offset(self.curser));
+        self.curser += self.page_size();
+        Ok(())
     }
 
-    pub fn write_page_at_offset(&mut self, page: Page, offset: &Offset) -> Result<(), Error> {
-        self.file.seek(SeekFrom::Start(offset.0 as u64))?;
-        self.file.write_all(&page.get_data())?;
-        Ok(())
+    /// seal returns the bytes to store for the page with a given id written at a given offset,
+    /// which are encrypted if the pager has a cipher.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn seal(&self, page: &Page, id: &PageId, offset: usize) -> Result<Vec<u8>, Error> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.encrypt(&page.get_data(), id.0, offset);
+        }
+        Ok(page.get_data().to_vec())
+    }
+
+    /// seal_record returns the bytes to log as the metadata of a WAL record, given the rest of
+    /// the record (see `WalRecord::authenticated_data`). If the pager has a cipher, the metadata
+    /// is encrypted and the rest of the record is authenticated alongside it, so that even
+    /// empty metadata is logged for a tampered record to be detected.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub fn seal_record(&self, metadata: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Error> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.encrypt_record(&metadata, aad);
+        }
+        Ok(metadata)
+    }
+
+    /// open_record returns the metadata of a WAL record given the record read back.
+    /// Fails with `Error::Corrupted` if the record was tampered with, as far as the pager
+    /// can tell: only the records of encrypted trees are authenticated.
+    pub fn open_record(&self, record: &WalRecord) -> Result<Vec<u8>, Error> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            let aad = WalRecord::authenticated_data(record.sequence, record.root, &record.pages);
+            return cipher.decrypt_record(&record.metadata, &aad);
+        }
+        Ok(record.metadata.clone())
+    }
+
+    /// changes returns the pages written since the last commit alongside their new offsets,
+    /// and the offsets of the committed copies they replace if any.
+    /// Pages found by their ids alone are left out, as there is no page table to update.
+    pub fn changes(&self) -> Vec<PageWrite> {
+        if !self.page_table {
+            return Vec::new();
+        }
+        self.dirty
+            .iter()
+            .map(|(id, offset)| (*id, offset.clone(), self.pages.get(id).cloned()))
+            .collect()
+    }
+
+    /// commit makes the pages written since the last commit replace their committed copies.
+    /// The pages have to be synced, and their changes logged, beforehand.
+    pub fn commit(&mut self) {
+        let dirty = std::mem::take(&mut self.dirty);
+        if self.page_table {
+            self.pages.extend(dirty);
+        }
+        self.committed = self.curser;
+    }
+
+    /// rollback discards the pages written since the last commit.
+    pub fn rollback(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// apply applies the changes of a commit read back from the WAL.
+    pub fn apply(&mut self, changes: &[PageWrite]) {
+        for (id, offset, _) in changes {
+            self.pages.insert(*id, offset.clone());
+            self.next_id = self.next_id.max(id.0 + 1);
+        }
+    }
+
+    /// storage returns the storage holding the pages.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// sync flushes all written pages to durable storage.
+    /// Pages have to be synced before a commit referring to them is logged.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.storage.sync()
     }
 }
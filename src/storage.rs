@@ -0,0 +1,359 @@
+use crate::error::Error;
+use memmap::MmapMut;
+use std::cmp;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Storage is a random access byte store underneath the pager and the write-ahead-log.
+/// Writes are not guaranteed to be durable until `sync` returns.
+///
+/// Implement it to keep a tree in a store of your own (e.g. an encrypted or a remote one)
+/// and build the tree with `BTreeBuilder::build_with_storage`.
+pub trait Storage {
+    /// read_at fills the given buffer with the bytes starting at offset.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error>;
+
+    /// write_at writes the given bytes at offset, growing the store if needed.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error>;
+
+    /// len returns the size of the store in bytes.
+    fn len(&mut self) -> Result<u64, Error>;
+
+    /// is_empty returns true if the store holds no bytes.
+    fn is_empty(&mut self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// sync flushes all previous writes to durable storage.
+    fn sync(&mut self) -> Result<(), Error>;
+
+    /// truncate shrinks (or grows) the store to the given size.
+    fn truncate(&mut self, len: u64) -> Result<(), Error>;
+}
+
+/// FileStorage is a Storage backed by a file on disk.
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    /// new opens the file at a given path for reading and writing, creating it if needed.
+    pub fn new(path: &Path) -> Result<FileStorage, Error> {
+        let fd = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(FileStorage { file: fd })
+    }
+
+    /// open_read_only opens the existing file at a given path for reading only,
+    /// so that it can be read off a read-only mount or without write permission.
+    pub fn open_read_only(path: &Path) -> Result<FileStorage, Error> {
+        let fd = OpenOptions::new().read(true).open(path)?;
+        Ok(FileStorage { file: fd })
+    }
+
+    /// try_lock takes an exclusive advisory lock on the file, which is held until the storage
+    /// is dropped. Fails with `Error::Locked` if the file is already locked by anyone else.
+    pub fn try_lock(&self) -> Result<(), Error> {
+        lock(&self.file, false)
+    }
+
+    /// try_lock_shared takes a shared advisory lock on the file, which is held until the storage
+    /// is dropped. Fails with `Error::Locked` if the file is exclusively locked by anyone else.
+    pub fn try_lock_shared(&self) -> Result<(), Error> {
+        lock(&self.file, true)
+    }
+}
+
+/// lock takes a shared or exclusive advisory lock on a file, without waiting for it.
+fn lock(file: &File, shared: bool) -> Result<(), Error> {
+    let res = match shared {
+        true => file.try_lock_shared(),
+        false => file.try_lock(),
+    };
+    match res {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(Error::Locked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)?;
+        Ok(())
+    }
+
+    fn len(&mut self) -> Result<u64, Error> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.file.set_len(len)?;
+        Ok(())
+    }
+}
+
+/// MemoryStorage is a Storage backed by a growable buffer in memory.
+/// Nothing is ever persisted, which makes it handy for tests and caches.
+#[derive(Default)]
+pub struct MemoryStorage {
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// as_bytes returns the content of the storage.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        let offset = offset as usize;
+        if self.data.len() < offset + buf.len() {
+            return Err(Error::UnexpectedError);
+        }
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        let offset = offset as usize;
+        if self.data.len() < offset + buf.len() {
+            self.data.resize(offset + buf.len(), 0x00);
+        }
+        self.data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&mut self) -> Result<u64, Error> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.data.resize(len as usize, 0x00);
+        Ok(())
+    }
+}
+
+/// MmapStorage is a Storage backed by a memory mapped file on disk.
+///
+/// The file is grown (and remapped) in chunks as writes go past its end, doubling its size
+/// every time, so that appending to it remaps it a logarithmic number of times. The file is
+/// trimmed back to the bytes written when the storage is dropped; a crash might leave zeros
+/// past them, which the pager and the WAL skip over as they would a torn write.
+///
+/// The file is exclusively locked while it is mapped, see `MmapStorage::new`. The lock is
+/// advisory: the file must not be truncated by anyone who does not take it either, as reading
+/// a mapping past the end of its file crashes the process.
+pub struct MmapStorage {
+    file: File,
+    /// An empty file cannot be mapped, in which case there is no mapping.
+    mmap: Option<MmapMut>,
+    /// The number of bytes written, the mapping might span more.
+    len: u64,
+}
+
+/// The smallest size a mapping is grown to.
+const MIN_MMAP_SIZE: u64 = 64 * 1024;
+
+impl MmapStorage {
+    /// new maps the file at a given path, creating it if needed, and takes an exclusive
+    /// advisory lock on it until the storage is dropped. Fails with `Error::Locked` if the file
+    /// is already locked by anyone else.
+    pub fn new(path: &Path) -> Result<MmapStorage, Error> {
+        let fd = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        lock(&fd, false)?;
+        let len = fd.metadata()?.len();
+        let mut storage = MmapStorage {
+            file: fd,
+            mmap: None,
+            len,
+        };
+        storage.remap()?;
+        Ok(storage)
+    }
+
+    /// remap maps the file anew following a change of its size.
+    fn remap(&mut self) -> Result<(), Error> {
+        self.mmap = None;
+        if self.file.metadata()?.len() > 0 {
+            // Safety: the file is locked by this storage, and is only resized through it.
+            self.mmap = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        }
+        Ok(())
+    }
+
+    fn resize(&mut self, len: u64) -> Result<(), Error> {
+        // Unmap before resizing so that the mapping never outlives the end of the file.
+        self.mmap = None;
+        self.file.set_len(len)?;
+        self.remap()
+    }
+
+    /// capacity returns the number of bytes mapped.
+    fn capacity(&self) -> u64 {
+        self.mmap.as_ref().map_or(0, |mmap| mmap.len() as u64)
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        if self.len < offset + buf.len() as u64 {
+            return Err(Error::UnexpectedError);
+        }
+        let offset = offset as usize;
+        let mmap = self.mmap.as_ref().ok_or(Error::UnexpectedError)?;
+        buf.copy_from_slice(&mmap[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        let end = offset + buf.len() as u64;
+        if self.capacity() < end {
+            let capacity = cmp::max(end, cmp::max(2 * self.capacity(), MIN_MMAP_SIZE));
+            self.resize(capacity)?;
+        }
+        let offset = offset as usize;
+        let mmap = self.mmap.as_mut().ok_or(Error::UnexpectedError)?;
+        mmap[offset..offset + buf.len()].copy_from_slice(buf);
+        self.len = cmp::max(self.len, end);
+        Ok(())
+    }
+
+    fn len(&mut self) -> Result<u64, Error> {
+        Ok(self.len)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        if let Some(mmap) = &self.mmap {
+            mmap.flush()?;
+        }
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.resize(len)?;
+        self.len = len;
+        Ok(())
+    }
+}
+
+impl Drop for MmapStorage {
+    fn drop(&mut self) {
+        // Trim the chunk the file was last grown by, a failure only leaves zeros behind.
+        if self.capacity() > self.len {
+            self.mmap = None;
+            let _ = self.file.set_len(self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::storage::{FileStorage, MemoryStorage, MmapStorage, Storage};
+    use std::fs;
+    use std::path::Path;
+
+    fn round_trip<S: Storage>(mut storage: S) -> Result<(), Error> {
+        assert_eq!(storage.len()?, 0);
+        storage.write_at(b"hello", 0)?;
+        storage.write_at(b"world", 10)?;
+        storage.sync()?;
+        assert_eq!(storage.len()?, 15);
+
+        let mut buf = [0x00; 15];
+        storage.read_at(&mut buf, 0)?;
+        assert_eq!(&buf, b"hello\0\0\0\0\0world");
+        // Reading past the end fails.
+        assert!(storage.read_at(&mut buf, 1).is_err());
+
+        storage.truncate(5)?;
+        assert_eq!(storage.len()?, 5);
+        let mut buf = [0x00; 5];
+        storage.read_at(&mut buf, 0)?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    fn clean(path: &Path) -> Result<(), Error> {
+        fs::create_dir_all(path.parent().ok_or(Error::UnexpectedError)?)?;
+        let _ = fs::remove_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_storage_works() -> Result<(), Error> {
+        round_trip(MemoryStorage::new())
+    }
+
+    #[test]
+    fn file_storage_works() -> Result<(), Error> {
+        let path = Path::new("/tmp/file_storage_works/data");
+        clean(path)?;
+        round_trip(FileStorage::new(path)?)
+    }
+
+    #[test]
+    fn mmap_storage_works() -> Result<(), Error> {
+        let path = Path::new("/tmp/mmap_storage_works/data");
+        clean(path)?;
+        round_trip(MmapStorage::new(path)?)?;
+
+        // The written bytes end up in the file, which is trimmed to them once dropped.
+        let mut storage = MmapStorage::new(path)?;
+        storage.write_at(b"!", 5)?;
+        storage.sync()?;
+        // The file is locked for as long as it is mapped.
+        assert!(matches!(MmapStorage::new(path), Err(Error::Locked)));
+        drop(storage);
+        assert_eq!(fs::read(path)?, b"hello!".to_vec());
+
+        // Appends grow the mapping in chunks rather than remapping the file on every write.
+        let mut storage = MmapStorage::new(path)?;
+        for i in 0..1000u64 {
+            storage.write_at(&[0x2A; 100], 6 + i * 100)?;
+        }
+        assert_eq!(storage.len()?, 100_006);
+        assert!(fs::metadata(path)?.len() > 100_006);
+        let mut buf = [0x00; 7];
+        storage.read_at(&mut buf, 0)?;
+        assert_eq!(&buf, b"hello!*");
+        assert!(storage.read_at(&mut buf, 100_000).is_err());
+        drop(storage);
+        assert_eq!(fs::metadata(path)?.len(), 100_006);
+        Ok(())
+    }
+}
@@ -1,42 +1,467 @@
 use crate::error::Error;
-use crate::node_type::Offset;
+use crate::node_type::{Offset, PageId};
 use crate::page_layout::PTR_SIZE;
+use crate::storage::Storage;
 use std::convert::TryFrom;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
 
-pub struct Wal {
-    file: File,
+/// WAL record header layout (Twenty bytes in total)
+///
+/// | Sequence number - 8 bytes | Payload length - 8 bytes | Checksum - 4 bytes |
+///
+/// The checksum is a CRC32 of the sequence number, the payload length and the payload.
+pub const WAL_SEQUENCE_OFFSET: usize = 0;
+pub const WAL_SEQUENCE_SIZE: usize = 8;
+pub const WAL_PAYLOAD_LEN_OFFSET: usize = WAL_SEQUENCE_OFFSET + WAL_SEQUENCE_SIZE;
+pub const WAL_PAYLOAD_LEN_SIZE: usize = 8;
+pub const WAL_CHECKSUM_OFFSET: usize = WAL_PAYLOAD_LEN_OFFSET + WAL_PAYLOAD_LEN_SIZE;
+pub const WAL_CHECKSUM_SIZE: usize = 4;
+pub const WAL_RECORD_HEADER_SIZE: usize = WAL_CHECKSUM_OFFSET + WAL_CHECKSUM_SIZE;
+
+/// WAL record payload layout
+///
+/// | Root id - 8 bytes | Number of pages - 8 bytes | Pages - 24 bytes each | Commit metadata |
+///
+/// Each page is the id of a page written by the commit followed by its new offset and by
+/// the offset of the copy it replaces, which is all ones for a new page.
+pub const WAL_ROOT_ID_SIZE: usize = PTR_SIZE;
+pub const WAL_NUM_PAGES_SIZE: usize = PTR_SIZE;
+pub const WAL_PAGE_SIZE: usize = 3 * PTR_SIZE;
+const NO_OFFSET: usize = usize::MAX;
+
+/// PageWrite is a page written by a commit: its id, its new offset, and the offset of the
+/// copy it replaces unless the page is new.
+pub type PageWrite = (PageId, Offset, Option<Offset>);
+
+/// WalRecord is a single commit in the write-ahead-log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalRecord {
+    /// Sequence numbers start at one and grow by one with every commit.
+    pub sequence: u64,
+    /// The id of the root of the tree as of this commit.
+    pub root: PageId,
+    /// The pages written by this commit alongside their new and previous offsets.
+    pub pages: Vec<PageWrite>,
+    /// Opaque metadata stored alongside the root.
+    pub metadata: Vec<u8>,
 }
 
-impl Wal {
-    pub fn new(parent_directoy: PathBuf) -> Result<Self, Error> {
-        let fd = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .open(parent_directoy.join("wal"))?;
+impl WalRecord {
+    fn checksum(sequence: u64, payload: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&sequence.to_be_bytes());
+        hasher.update(&(payload.len() as u64).to_be_bytes());
+        hasher.update(payload);
+        hasher.finalize()
+    }
+
+    /// from_payload deserializes a record from its payload, returning None if it is malformed.
+    fn from_payload(sequence: u64, mut payload: Vec<u8>) -> Option<WalRecord> {
+        let read = |offset: usize| -> Option<usize> {
+            let bytes = payload.get(offset..offset + PTR_SIZE)?;
+            Some(usize::from_be_bytes(
+                <[u8; PTR_SIZE]>::try_from(bytes).ok()?,
+            ))
+        };
+        let root = PageId(read(0)?);
+        let num_pages = read(WAL_ROOT_ID_SIZE)?;
+        let mut offset = WAL_ROOT_ID_SIZE + WAL_NUM_PAGES_SIZE;
+        let mut pages = Vec::new();
+        for _ in 0..num_pages {
+            let previous = Some(read(offset + 2 * PTR_SIZE)?).filter(|&o| o != NO_OFFSET);
+            pages.push((
+                PageId(read(offset)?),
+                Offset(read(offset + PTR_SIZE)?),
+                previous.map(Offset),
+            ));
+            offset += WAL_PAGE_SIZE;
+        }
+        Some(WalRecord {
+            sequence,
+            root,
+            pages,
+            metadata: payload.split_off(offset),
+        })
+    }
+
+    /// authenticated_data returns the fields of a record but for its metadata, which the metadata
+    /// of the records of encrypted trees is bound to, see `Pager::seal_record`.
+    pub fn authenticated_data(sequence: u64, root: PageId, pages: &[PageWrite]) -> Vec<u8> {
+        let mut data = sequence.to_be_bytes().to_vec();
+        data.extend_from_slice(&root.0.to_be_bytes());
+        data.extend_from_slice(&pages.len().to_be_bytes());
+        for page in pages {
+            encode_page(&mut data, page);
+        }
+        data
+    }
+
+    /// to_bytes serializes the record into its on-disk format.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(
+            WAL_ROOT_ID_SIZE
+                + WAL_NUM_PAGES_SIZE
+                + self.pages.len() * WAL_PAGE_SIZE
+                + self.metadata.len(),
+        );
+        payload.extend_from_slice(&self.root.0.to_be_bytes());
+        payload.extend_from_slice(&self.pages.len().to_be_bytes());
+        for page in self.pages.iter() {
+            encode_page(&mut payload, page);
+        }
+        payload.extend_from_slice(&self.metadata);
+
+        let mut raw = Vec::with_capacity(WAL_RECORD_HEADER_SIZE + payload.len());
+        raw.extend_from_slice(&self.sequence.to_be_bytes());
+        raw.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        raw.extend_from_slice(&WalRecord::checksum(self.sequence, &payload).to_be_bytes());
+        raw.extend_from_slice(&payload);
+        raw
+    }
+}
+
+/// encode_page appends a page written by a commit to a record being serialized.
+fn encode_page(data: &mut Vec<u8>, (id, offset, previous): &PageWrite) {
+    data.extend_from_slice(&id.0.to_be_bytes());
+    data.extend_from_slice(&offset.0.to_be_bytes());
+    let previous = previous.as_ref().map_or(NO_OFFSET, |previous| previous.0);
+    data.extend_from_slice(&previous.to_be_bytes());
+}
+
+/// Wal is an append-only log of the commits to the tree.
+/// Replaying its records in order rebuilds the page table, and the last one points at the current root.
+pub struct Wal<S: Storage> {
+    storage: S,
+    /// The file offset right past the last valid record.
+    end_offset: u64,
+    /// The sequence number of the last valid record (zero when empty).
+    sequence: u64,
+    /// The file offset of every valid record, by sequence number (the first one being one).
+    offsets: Vec<u64>,
+    /// The records read from the log which were not replayed yet.
+    unreplayed: Vec<WalRecord>,
+}
+
+impl<S: Storage> Wal<S> {
+    /// new opens the log held by the given storage and recovers it,
+    /// dropping any torn or corrupted records at its tail.
+    /// The caller is expected to be the only writer of the log.
+    pub fn new(storage: S) -> Result<Self, Error> {
+        let mut wal = Wal::open_read_only(storage)?;
+        wal.recover()?;
+        Ok(wal)
+    }
+
+    /// open_read_only opens the log held by the given storage without ever modifying it.
+    /// Records appended to the log by its writer are picked up as they are committed.
+    pub fn open_read_only(storage: S) -> Result<Self, Error> {
+        let mut wal = Self {
+            storage,
+            end_offset: 0,
+            sequence: 0,
+            offsets: Vec::new(),
+            unreplayed: Vec::new(),
+        };
+        wal.scan()?;
+        Ok(wal)
+    }
+
+    /// scan reads the records past the last valid record, up to the last record whose header and
+    /// checksum are valid and whose sequence number follows its predecessor.
+    fn scan(&mut self) -> Result<(), Error> {
+        let file_len = self.storage.len()?;
+        while let Some((record, next_offset)) = self.read_record(self.end_offset, file_len)? {
+            if record.sequence != self.sequence + 1 {
+                break;
+            }
+            self.sequence = record.sequence;
+            self.offsets.push(self.end_offset);
+            self.end_offset = next_offset;
+            self.unreplayed.push(record);
+        }
+        Ok(())
+    }
+
+    /// recover truncates anything past the last valid record, i.e. a record left torn or
+    /// corrupted by a crash.
+    fn recover(&mut self) -> Result<(), Error> {
+        if self.end_offset < self.storage.len()? {
+            self.storage.truncate(self.end_offset)?;
+            self.storage.sync()?;
+        }
+        Ok(())
+    }
+
+    /// read_record reads the record starting at a given offset, returning it alongside
+    /// the offset of the following record. Returns None if the record is torn or corrupted.
+    fn read_record(
+        &mut self,
+        offset: u64,
+        file_len: u64,
+    ) -> Result<Option<(WalRecord, u64)>, Error> {
+        if file_len < offset + WAL_RECORD_HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+        let mut header: [u8; WAL_RECORD_HEADER_SIZE] = [0x00; WAL_RECORD_HEADER_SIZE];
+        self.storage.read_at(&mut header, offset)?;
+
+        let mut sequence = [0x00; WAL_SEQUENCE_SIZE];
+        sequence
+            .copy_from_slice(&header[WAL_SEQUENCE_OFFSET..WAL_SEQUENCE_OFFSET + WAL_SEQUENCE_SIZE]);
+        let sequence = u64::from_be_bytes(sequence);
+        let mut payload_len = [0x00; WAL_PAYLOAD_LEN_SIZE];
+        payload_len.copy_from_slice(
+            &header[WAL_PAYLOAD_LEN_OFFSET..WAL_PAYLOAD_LEN_OFFSET + WAL_PAYLOAD_LEN_SIZE],
+        );
+        let payload_len = u64::from_be_bytes(payload_len);
+        let mut checksum = [0x00; WAL_CHECKSUM_SIZE];
+        checksum
+            .copy_from_slice(&header[WAL_CHECKSUM_OFFSET..WAL_CHECKSUM_OFFSET + WAL_CHECKSUM_SIZE]);
+        let checksum = u32::from_be_bytes(checksum);
+
+        let payload_offset = offset + WAL_RECORD_HEADER_SIZE as u64;
+        if file_len - payload_offset < payload_len {
+            return Ok(None);
+        }
+        let mut payload = vec![0x00; payload_len as usize];
+        self.storage.read_at(&mut payload, payload_offset)?;
+        if WalRecord::checksum(sequence, &payload) != checksum {
+            return Ok(None);
+        }
+        Ok(WalRecord::from_payload(sequence, payload)
+            .map(|record| (record, payload_offset + payload_len)))
+    }
+
+    /// storage returns the storage holding the log.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// replay returns the records which were not replayed yet, in order: every record
+    /// in the log on the first call, and later on the records appended by another writer.
+    /// Records committed through this log are never replayed.
+    pub fn replay(&mut self) -> Result<Vec<WalRecord>, Error> {
+        self.scan()?;
+        Ok(std::mem::take(&mut self.unreplayed))
+    }
 
-        Ok(Self { file: fd })
+    /// sequence returns the sequence number of the last record (zero when empty).
+    pub fn sequence(&self) -> u64 {
+        self.sequence
     }
 
-    pub fn get_root(&mut self) -> Result<Offset, Error> {
-        let mut buff: [u8; PTR_SIZE] = [0x00; PTR_SIZE];
-        let file_len = self.file.seek(SeekFrom::End(0))? as usize;
-        let mut root_offset: usize = 0;
-        if file_len > 0 {
-            root_offset = (file_len / PTR_SIZE - 1) * PTR_SIZE;
+    /// records_since reads back the records past a given sequence number, in order,
+    /// up to the last record read or committed through this log. Reading starts right at
+    /// the first of them, the records up to the sequence number are not read.
+    pub fn records_since(&mut self, sequence: u64) -> Result<Vec<WalRecord>, Error> {
+        let mut records = Vec::new();
+        let mut offset = match self.offsets.get(sequence as usize) {
+            Some(offset) => *offset,
+            None => return Ok(records),
+        };
+        while offset < self.end_offset {
+            let (record, next_offset) = self
+                .read_record(offset, self.end_offset)?
+                .ok_or(Error::Corrupted)?;
+            records.push(record);
+            offset = next_offset;
         }
-        self.file.seek(SeekFrom::Start(root_offset as u64))?;
-        self.file.read_exact(&mut buff)?;
-        Offset::try_from(buff)
+        Ok(records)
     }
 
-    pub fn set_root(&mut self, offset: Offset) -> Result<(), Error> {
-        self.file.seek(SeekFrom::End(0))?;
-        self.file.write_all(&offset.0.to_be_bytes())?;
+    /// commit appends a new record pointing at the given root, listing the pages written since
+    /// the previous commit and carrying the given metadata, and flushes it to disk.
+    pub fn commit(
+        &mut self,
+        root: PageId,
+        pages: Vec<PageWrite>,
+        metadata: Vec<u8>,
+    ) -> Result<(), Error> {
+        let record = WalRecord {
+            sequence: self.sequence + 1,
+            root,
+            pages,
+            metadata,
+        };
+        let raw = record.to_bytes();
+        self.storage.write_at(&raw, self.end_offset)?;
+        self.storage.sync()?;
+        self.offsets.push(self.end_offset);
+        self.end_offset += raw.len() as u64;
+        self.sequence = record.sequence;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::node_type::{Offset, PageId};
+    use crate::storage::FileStorage;
+    use crate::wal::{Wal, WAL_RECORD_HEADER_SIZE};
+    use std::fs::{self, OpenOptions};
+    use std::path::Path;
+
+    fn clean(directory: &Path) -> Result<(), Error> {
+        fs::create_dir_all(directory)?;
+        let _ = fs::remove_file(directory.join("wal"));
+        Ok(())
+    }
+
+    fn open(directory: &Path) -> Result<Wal<FileStorage>, Error> {
+        Wal::new(FileStorage::new(&directory.join("wal"))?)
+    }
+
+    #[test]
+    fn empty_wal_has_no_records() -> Result<(), Error> {
+        let directory = Path::new("/tmp/wal_empty_wal_has_no_records");
+        clean(directory)?;
+
+        let mut wal = open(directory)?;
+        assert!(wal.replay()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_replays_records() -> Result<(), Error> {
+        let directory = Path::new("/tmp/wal_reopen_replays_records");
+        clean(directory)?;
+
+        let mut wal = open(directory)?;
+        wal.commit(PageId(1), vec![(PageId(1), Offset(0), None)], Vec::new())?;
+        wal.commit(
+            PageId(2),
+            vec![
+                (PageId(1), Offset(4096), Some(Offset(0))),
+                (PageId(2), Offset(8192), None),
+            ],
+            b"metadata".to_vec(),
+        )?;
+        // Commits are not replayed by their own writer.
+        assert!(wal.replay()?.is_empty());
+        drop(wal);
+
+        let mut wal = open(directory)?;
+        let records = wal.replay()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].sequence, 1);
+        assert_eq!(records[0].root, PageId(1));
+        assert_eq!(records[0].pages, vec![(PageId(1), Offset(0), None)]);
+        assert_eq!(records[1].sequence, 2);
+        assert_eq!(records[1].root, PageId(2));
+        assert_eq!(
+            records[1].pages,
+            vec![
+                (PageId(1), Offset(4096), Some(Offset(0))),
+                (PageId(2), Offset(8192), None),
+            ]
+        );
+        assert_eq!(records[1].metadata, b"metadata".to_vec());
+        assert!(wal.replay()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn records_are_read_back_from_a_sequence_number() -> Result<(), Error> {
+        let directory = Path::new("/tmp/wal_records_are_read_back_from_a_sequence_number");
+        clean(directory)?;
+
+        let mut wal = open(directory)?;
+        for id in 1..=5 {
+            wal.commit(PageId(id), Vec::new(), vec![id as u8])?;
+        }
+        assert_eq!(wal.sequence(), 5);
+        let records = wal.records_since(3)?;
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].sequence, &records[0].metadata), (4, &vec![4]));
+        assert_eq!((records[1].sequence, &records[1].metadata), (5, &vec![5]));
+        assert_eq!(wal.records_since(0)?.len(), 5);
+        assert!(wal.records_since(5)?.is_empty());
+        assert!(wal.records_since(7)?.is_empty());
+
+        // Records are found again once the log is reopened.
+        drop(wal);
+        let mut wal = open(directory)?;
+        assert_eq!(wal.records_since(4)?[0].metadata, vec![5]);
+        Ok(())
+    }
+
+    #[test]
+    fn readers_replay_new_records() -> Result<(), Error> {
+        let directory = Path::new("/tmp/wal_readers_replay_new_records");
+        clean(directory)?;
+
+        let mut wal = open(directory)?;
+        wal.commit(PageId(1), Vec::new(), Vec::new())?;
+        let mut reader = Wal::open_read_only(FileStorage::new(&directory.join("wal"))?)?;
+        assert_eq!(reader.replay()?.len(), 1);
+
+        wal.commit(PageId(2), Vec::new(), Vec::new())?;
+        let records = reader.replay()?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].root, PageId(2));
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_truncates_torn_tail() -> Result<(), Error> {
+        let directory = Path::new("/tmp/wal_recovery_truncates_torn_tail");
+        clean(directory)?;
+
+        let mut wal = open(directory)?;
+        wal.commit(PageId(1), Vec::new(), Vec::new())?;
+        wal.commit(PageId(2), Vec::new(), Vec::new())?;
+        drop(wal);
+
+        // Simulate a crash in the middle of appending the second record.
+        let path = directory.join("wal");
+        let len = fs::metadata(&path)?.len();
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(len - 3)?;
+        drop(file);
+
+        let mut wal = open(directory)?;
+        let records = wal.replay()?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].root, PageId(1));
+        assert_eq!(
+            fs::metadata(&path)?.len(),
+            (WAL_RECORD_HEADER_SIZE + 16) as u64
+        );
+
+        // New commits continue from the last valid record.
+        wal.commit(PageId(3), Vec::new(), Vec::new())?;
+        drop(wal);
+        let mut wal = open(directory)?;
+        let records = wal.replay()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].sequence, 2);
+        assert_eq!(records[1].root, PageId(3));
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_drops_corrupted_records() -> Result<(), Error> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let directory = Path::new("/tmp/wal_recovery_drops_corrupted_records");
+        clean(directory)?;
+
+        let mut wal = open(directory)?;
+        wal.commit(PageId(1), Vec::new(), Vec::new())?;
+        wal.commit(PageId(2), Vec::new(), Vec::new())?;
+        drop(wal);
+
+        // Flip a byte of the second record's page count.
+        let path = directory.join("wal");
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::End(-1))?;
+        file.write_all(&[0xff])?;
+        drop(file);
+
+        let mut wal = open(directory)?;
+        let records = wal.replay()?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].root, PageId(1));
         Ok(())
     }
 }